@@ -0,0 +1,142 @@
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+/// Palette sizes accepted by the `count` query param
+pub const MAX_COLORS: u8 = 16;
+pub const DEFAULT_COLORS: u8 = 5;
+/// Pixels with less opacity don't count towards any color
+const MIN_ALPHA: u8 = 128;
+const ITERATIONS: usize = 20;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Swatch {
+    /// Hex color, e.g. `#1a2b3c`
+    pub color: String,
+    /// Share of the visible pixels closest to this color, from 0 to 1
+    pub proportion: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Colors {
+    pub dominant: String,
+    /// Sorted from the most to the least common color
+    pub palette: Vec<Swatch>,
+}
+
+impl Colors {
+    /// Clusters the pixels of a downscaled copy of the image with k-means
+    pub fn new(img: &DynamicImage, count: u8) -> Self {
+        let thumb = img.thumbnail(64, 64).to_rgba8();
+        let pixels: Vec<[f32; 3]> = thumb
+            .pixels()
+            .filter(|p| p[3] >= MIN_ALPHA)
+            .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+            .collect();
+        //fully transparent images have no colors to extract
+        if pixels.is_empty() {
+            return Self {
+                dominant: to_hex(&[0.0; 3]),
+                palette: vec![],
+            };
+        }
+
+        let (centroids, sizes) = kmeans(&pixels, count as usize);
+        let mut palette: Vec<Swatch> = centroids
+            .iter()
+            .zip(sizes)
+            .filter(|(_, size)| *size > 0)
+            .map(|(c, size)| Swatch {
+                color: to_hex(c),
+                proportion: size as f32 / pixels.len() as f32,
+            })
+            .collect();
+        palette.sort_by(|a, b| b.proportion.total_cmp(&a.proportion));
+        Self {
+            dominant: palette[0].color.clone(),
+            palette,
+        }
+    }
+}
+
+/// Returns the centroids and the number of pixels assigned to each one. Centroids are seeded
+/// with the farthest point heuristic, so the same image always gets the same palette.
+fn kmeans(pixels: &[[f32; 3]], k: usize) -> (Vec<[f32; 3]>, Vec<usize>) {
+    let mut centroids = vec![mean(pixels)];
+    let mut nearest: Vec<f32> = pixels.iter().map(|p| distance(p, &centroids[0])).collect();
+    while centroids.len() < k {
+        let (i, d) = nearest
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap_or((0, &0.0));
+        //fewer distinct colors than requested
+        if *d == 0.0 {
+            break;
+        }
+        let c = pixels[i];
+        for (n, p) in nearest.iter_mut().zip(pixels) {
+            *n = n.min(distance(p, &c));
+        }
+        centroids.push(c);
+    }
+
+    let mut labels = vec![0; pixels.len()];
+    let mut sizes = vec![0; centroids.len()];
+    for _ in 0..ITERATIONS {
+        let mut changed = false;
+        for (label, p) in labels.iter_mut().zip(pixels) {
+            let c = closest(p, &centroids);
+            changed |= *label != c;
+            *label = c;
+        }
+        let mut sums = vec![[0.0; 3]; centroids.len()];
+        sizes = vec![0; centroids.len()];
+        for (label, p) in labels.iter().zip(pixels) {
+            for (s, v) in sums[*label].iter_mut().zip(p) {
+                *s += v;
+            }
+            sizes[*label] += 1;
+        }
+        for ((centroid, sum), size) in centroids.iter_mut().zip(&sums).zip(&sizes) {
+            if *size > 0 {
+                *centroid = sum.map(|s| s / *size as f32);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    (centroids, sizes)
+}
+
+fn closest(p: &[f32; 3], centroids: &[[f32; 3]]) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .min_by(|a, b| distance(p, a.1).total_cmp(&distance(p, b.1)))
+        .map(|(i, _)| i)
+        .unwrap_or_default()
+}
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum()
+}
+
+fn mean(pixels: &[[f32; 3]]) -> [f32; 3] {
+    let mut sum = [0.0; 3];
+    for p in pixels {
+        for (s, v) in sum.iter_mut().zip(p) {
+            *s += v;
+        }
+    }
+    sum.map(|s| s / pixels.len() as f32)
+}
+
+fn to_hex(c: &[f32; 3]) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        c[0].round() as u8,
+        c[1].round() as u8,
+        c[2].round() as u8
+    )
+}
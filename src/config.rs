@@ -1,3 +1,5 @@
+use crate::img::{Encoding, Filter};
+use crate::metadata::Strip;
 use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
 use serde_derive::{Deserialize, Serialize};
 
@@ -16,6 +18,8 @@ pub struct AppConfig {
     pub allow_any_origin: bool,
     pub twitter: Option<TwitterConfig>,
     pub cloudfront: Option<CloudfrontConfig>,
+    pub avif: Option<AvifConfig>,
+    pub video: Option<VideoConfig>,
     pub origins: Vec<Origin>,
     pub obj_deny_list: Option<Vec<String>>,
     pub url_deny_list: Option<Vec<String>>,
@@ -30,6 +34,20 @@ pub struct TwitterConfig {
     pub cache: CacheConfig,
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AvifConfig {
+    pub speed: u8,
+    pub quality: u8,
+}
+/// Limits for the frames extracted from videos
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VideoConfig {
+    pub fps: u32,
+    pub max_duration: u32,
+    pub max_frames: u32,
+    /// Largest width or height of the extracted frames, which are all kept in memory
+    pub max_edge: u32,
+}
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct MediaConfig {
     cache: CacheConfig,
 }
@@ -44,6 +62,10 @@ pub struct Origin {
     pub name: String,
     pub endpoint: String,
     pub cache: CacheConfig,
+    pub default_quality: Option<u8>,
+    pub default_strip: Option<Strip>,
+    pub convert_to_srgb: Option<bool>,
+    pub default_filter: Option<Filter>,
 }
 impl AppConfig {
     pub fn validate_origin(&self, origin: &str) -> Option<Origin> {
@@ -57,14 +79,56 @@ impl AppConfig {
             denied.into_iter().find(|u| url.contains(u))
         }
     }
-    pub fn validate_scale(&self, scale: Option<u32>) -> Option<u32> {
+    /// Validates a requested size and multiplies it by the device pixel ratio. The width, or
+    /// the height when there is no width, is snapped to the smallest allowed size that covers
+    /// the product, or the largest one. The other side is scaled by the same factor so boxed
+    /// sizes keep their aspect ratio at every dpr. Missing sides are 0, and the first side
+    /// that isn't allowed is returned with its value.
+    pub fn validate_size(
+        &self,
+        width: Option<u32>,
+        height: Option<u32>,
+        dpr: f32,
+    ) -> Result<(u32, u32), (&'static str, u32)> {
         let allowed = self.allowed_sizes.clone().unwrap_or_default();
-        if allowed.is_empty() || scale.is_none() {
-            Some(scale.unwrap_or(0))
-        } else {
+        for (param, scale) in [("width", width), ("height", height)] {
+            if let Some(s) = scale {
+                if !allowed.is_empty() && !allowed.contains(&s) {
+                    return Err((param, s));
+                }
+            }
+        }
+        let snap = |scale: u32| {
+            let effective = (scale as f32 * dpr).round() as u32;
+            if allowed.is_empty() || effective == scale {
+                return effective;
+            }
             allowed
-                .into_iter()
-                .find(|s| s == &scale.unwrap_or_default())
+                .iter()
+                .filter(|s| **s >= effective)
+                .min()
+                .or_else(|| allowed.iter().max())
+                .copied()
+                .unwrap_or(effective)
+        };
+        let size = match (width.filter(|w| *w > 0), height.filter(|h| *h > 0)) {
+            (Some(w), Some(h)) => {
+                let snapped = snap(w);
+                let ratio = snapped as f32 / w as f32;
+                (snapped, ((h as f32 * ratio).round() as u32).max(1))
+            }
+            (Some(w), None) => (snap(w), 0),
+            (None, Some(h)) => (0, snap(h)),
+            (None, None) => (0, 0),
+        };
+        Ok(size)
+    }
+    pub fn encoding(&self) -> Encoding {
+        let avif = self.avif.clone().unwrap_or_default();
+        Encoding {
+            avif_speed: avif.speed,
+            avif_quality: avif.quality,
+            ..Default::default()
         }
     }
 }
@@ -74,12 +138,35 @@ impl Default for CacheConfig {
         Self { max_age: 31536000 }
     }
 }
+impl Default for AvifConfig {
+    fn default() -> Self {
+        let encoding = Encoding::default();
+        Self {
+            speed: encoding.avif_speed,
+            quality: encoding.avif_quality,
+        }
+    }
+}
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            fps: 25,
+            max_duration: 10,
+            max_frames: 250,
+            max_edge: 720,
+        }
+    }
+}
 impl Default for Origin {
     fn default() -> Self {
         Self {
             name: String::from("ipfs"),
             endpoint: String::from("https://ipfs.io/ipfs"),
             cache: CacheConfig::default(),
+            default_quality: None,
+            default_strip: None,
+            convert_to_srgb: None,
+            default_filter: None,
         }
     }
 }
@@ -98,6 +185,8 @@ impl Default for AppConfig {
             kvstore_uri: "http://127.0.0.1:5050".to_string(),
             allowed_sizes: None,
             cloudfront: None,
+            avif: None,
+            video: None,
             allow_any_origin: true,
             twitter: None,
             health_endpoint: String::from("/health"),
@@ -122,3 +211,46 @@ pub fn rustls_config() -> ClientConfig {
         .with_root_certificates(root_store)
         .with_no_client_auth()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allowed: &[u32]) -> AppConfig {
+        AppConfig {
+            allowed_sizes: Some(allowed.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn snaps_scaled_sizes() {
+        let cfg = config(&[300, 400, 500, 600]);
+        assert_eq!(cfg.validate_size(Some(400), None, 1.5), Ok((600, 0)));
+        assert_eq!(cfg.validate_size(None, Some(300), 1.5), Ok((0, 500)));
+        assert_eq!(cfg.validate_size(Some(400), None, 2.0), Ok((600, 0)));
+        assert_eq!(cfg.validate_size(None, None, 2.0), Ok((0, 0)));
+    }
+
+    #[test]
+    fn keeps_the_aspect_ratio_of_boxed_sizes() {
+        let cfg = config(&[300, 400, 500, 600]);
+        assert_eq!(cfg.validate_size(Some(400), Some(300), 1.5), Ok((600, 450)));
+        assert_eq!(cfg.validate_size(Some(500), Some(300), 1.5), Ok((600, 360)));
+        assert_eq!(cfg.validate_size(Some(400), Some(300), 1.0), Ok((400, 300)));
+    }
+
+    #[test]
+    fn rejects_sizes_not_allowed() {
+        let cfg = config(&[300, 400]);
+        assert_eq!(cfg.validate_size(Some(350), None, 1.0), Err(("width", 350)));
+        assert_eq!(
+            cfg.validate_size(Some(400), Some(10), 1.0),
+            Err(("height", 10))
+        );
+        assert_eq!(
+            AppConfig::default().validate_size(Some(350), Some(10), 2.0),
+            Ok((700, 20))
+        );
+    }
+}
@@ -1,181 +1,1964 @@
+use crate::config::VideoConfig;
+use crate::metadata::{self, Metadata, Strip, PNG_SIGNATURE};
 use crate::utils::*;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use cmd_lib::*;
-use image::{imageops::FilterType, io::Reader, EncodableLayout, ImageFormat};
-use log::{error, info};
+use image::{
+    codecs::{avif::AvifEncoder, png::PngDecoder},
+    imageops,
+    io::Reader,
+    AnimationDecoder, DynamicImage, EncodableLayout, GrayImage, ImageFormat, ImageOutputFormat,
+    Luma, RgbImage, RgbaImage,
+};
+use log::info;
+use mime::Mime;
 use mp4::TrackType;
-use png::ColorType;
-use resize::{Pixel, Type::Triangle};
+use png::{BitDepth, ColorType};
+use rayon::prelude::*;
+use resize::Pixel;
 use rgb::FromSlice;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::io::Cursor;
+use std::str::FromStr;
 use std::time::Instant;
 use webp_animation::prelude::*;
 
-pub fn resize_webp(data: &[u8], width: u32, animated: bool) -> Result<Vec<u8>> {
+/// How the source is fitted into the target box when both width and height are requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Keep aspect ratio and crop the overflow so the box is fully covered
+    Cover,
+    /// Keep aspect ratio and pad the remaining space so the whole image is visible
+    Contain,
+    /// Stretch to the exact box, ignoring aspect ratio
+    Fill,
+    /// Keep aspect ratio and stay within the box (never upscales)
+    Inside,
+    /// Keep aspect ratio and reach at least the box on both sides (never upscales)
+    Outside,
+}
+
+impl Default for Fit {
+    fn default() -> Self {
+        Self::Inside
+    }
+}
+
+impl fmt::Display for Fit {
+    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let fit = match self {
+            Self::Cover => "cover",
+            Self::Contain => "contain",
+            Self::Fill => "fill",
+            Self::Inside => "inside",
+            Self::Outside => "outside",
+        };
+        write!(out, "{}", fit)
+    }
+}
+
+/// Which part of the source is kept when cropping to an aspect ratio or covering a box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    /// Keep the area with the most detail
+    Entropy,
+}
+
+impl Default for Gravity {
+    fn default() -> Self {
+        Self::Center
+    }
+}
+
+impl fmt::Display for Gravity {
+    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let gravity = match self {
+            Self::Center => "center",
+            Self::North => "north",
+            Self::South => "south",
+            Self::East => "east",
+            Self::West => "west",
+            Self::Entropy => "entropy",
+        };
+        write!(out, "{}", gravity)
+    }
+}
+
+impl Gravity {
+    /// Offset of a `w`x`h` window inside `region`, relative to the region origin
+    fn offset(&self, region: Region, (w, h): (u32, u32), luma: Option<&GrayImage>) -> (u32, u32) {
+        let (dx, dy) = (region.width - w, region.height - h);
+        match (self, luma) {
+            (Self::North, _) => (dx / 2, 0),
+            (Self::South, _) => (dx / 2, dy),
+            (Self::East, _) => (dx, dy / 2),
+            (Self::West, _) => (0, dy / 2),
+            (Self::Entropy, Some(luma)) if (dx, dy) != (0, 0) => {
+                entropy_offset(luma, region, (w, h))
+            }
+            _ => (dx / 2, dy / 2),
+        }
+    }
+}
+
+/// Rectangle in source pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn full(width: u32, height: u32) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// Keeps the region inside a `w`x`h` image
+    fn clamp(&self, w: u32, h: u32) -> Self {
+        let (x, y) = (self.x.min(w - 1), self.y.min(h - 1));
+        Self {
+            x,
+            y,
+            width: self.width.min(w - x),
+            height: self.height.min(h - y),
+        }
+    }
+}
+
+/// Parses `x,y,width,height`
+impl FromStr for Region {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Region, Self::Err> {
+        let values = input
+            .split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()?;
+        match values[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Region {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(anyhow!("invalid crop region: {input}")),
+        }
+    }
+}
+
+/// Aspect ratio, as width to height
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aspect(pub u32, pub u32);
+
+/// Parses `width:height`
+impl FromStr for Aspect {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Aspect, Self::Err> {
+        match input.split_once(':') {
+            Some((w, h)) => match (w.trim().parse::<u32>()?, h.trim().parse::<u32>()?) {
+                (0, _) | (_, 0) => Err(anyhow!("invalid aspect ratio: {input}")),
+                (w, h) => Ok(Aspect(w, h)),
+            },
+            None => Err(anyhow!("invalid aspect ratio: {input}")),
+        }
+    }
+}
+
+/// RGBA color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub [u8; 4]);
+
+/// Parses hex colors as `rgb`, `rgba`, `rrggbb` or `rrggbbaa`, with an optional `#`
+impl FromStr for Color {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Color, Self::Err> {
+        let hex = input.trim().trim_start_matches('#');
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("invalid color: {input}"));
+        }
+        let digits = match hex.len() {
+            3 | 4 => hex.chars().flat_map(|c| [c, c]).collect::<String>(),
+            6 | 8 => hex.to_string(),
+            _ => return Err(anyhow!("invalid color: {input}")),
+        };
+        let mut rgba = [255; 4];
+        for (i, c) in rgba.iter_mut().enumerate().take(digits.len() / 2) {
+            *c = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(Color(rgba))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b, a] = self.0;
+        write!(f, "{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+}
+
+/// Still frame picked from a video
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frame {
+    First,
+    Middle,
+    /// Seconds from the start
+    At(f32),
+}
+
+impl Frame {
+    /// Position in seconds for a video of the given duration, kept inside the video
+    fn seconds(&self, duration: f32) -> f32 {
+        let last = (duration - 0.1).max(0.0);
+        match self {
+            Frame::First => 0.0,
+            Frame::Middle => duration / 2.0,
+            Frame::At(t) => t.min(last),
+        }
+    }
+}
+
+/// Parses `first`, `middle` or `t=<seconds>`
+impl FromStr for Frame {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Frame, Self::Err> {
+        match input.trim() {
+            "first" => Ok(Frame::First),
+            "middle" => Ok(Frame::Middle),
+            other => match other.strip_prefix("t=").map(|t| t.trim().parse::<f32>()) {
+                Some(Ok(t)) if t.is_finite() && t >= 0.0 => Ok(Frame::At(t)),
+                _ => Err(anyhow!("invalid frame: {input}")),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::First => write!(f, "first"),
+            Frame::Middle => write!(f, "middle"),
+            Frame::At(t) => write!(f, "t={}", t),
+        }
+    }
+}
+
+/// Resampling filter used by every resize path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    Nearest,
+    Triangle,
+    /// Catmull-Rom
+    Catmull,
+    Mitchell,
+    Lanczos3,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::Lanczos3
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let filter = match self {
+            Self::Nearest => "nearest",
+            Self::Triangle => "triangle",
+            Self::Catmull => "catmull",
+            Self::Mitchell => "mitchell",
+            Self::Lanczos3 => "lanczos3",
+        };
+        write!(out, "{}", filter)
+    }
+}
+
+impl Filter {
+    fn resize_type(&self) -> resize::Type {
+        match self {
+            Self::Nearest => resize::Type::Point,
+            Self::Triangle => resize::Type::Triangle,
+            Self::Catmull => resize::Type::Catrom,
+            Self::Mitchell => resize::Type::Mitchell,
+            Self::Lanczos3 => resize::Type::Lanczos3,
+        }
+    }
+
+    /// Flags of the ffmpeg scale filter
+    fn ffmpeg_flags(&self) -> &'static str {
+        match self {
+            Self::Nearest => "neighbor",
+            Self::Triangle => "bilinear",
+            Self::Catmull => "bicubic:param0=0:param1=0.5",
+            Self::Mitchell => "bicubic:param0=1/3:param1=1/3",
+            Self::Lanczos3 => "lanczos",
+        }
+    }
+}
+
+/// Clockwise rotation requested with the `rotate` query param
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Rotate {
+    #[serde(rename = "90")]
+    Deg90,
+    #[serde(rename = "180")]
+    Deg180,
+    #[serde(rename = "270")]
+    Deg270,
+}
+
+impl Rotate {
+    fn degrees(&self) -> u16 {
+        match self {
+            Self::Deg90 => 90,
+            Self::Deg180 => 180,
+            Self::Deg270 => 270,
+        }
+    }
+}
+
+impl fmt::Display for Rotate {
+    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(out, "{}", self.degrees())
+    }
+}
+
+/// Mirroring requested with the `flip` query param
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Flip {
+    #[serde(rename = "h")]
+    Horizontal,
+    #[serde(rename = "v")]
+    Vertical,
+}
+
+impl fmt::Display for Flip {
+    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let flip = match self {
+            Self::Horizontal => "h",
+            Self::Vertical => "v",
+        };
+        write!(out, "{}", flip)
+    }
+}
+
+/// Clockwise rotation followed by an optional horizontal flip. The EXIF orientation and any
+/// combination of `rotate` and `flip` reduce to one of these.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Orientation {
+    degrees: u16,
+    flip: bool,
+}
+
+impl Orientation {
+    /// Transformation that makes an image tagged with the EXIF orientation upright
+    fn from_exif(value: u32) -> Self {
+        let (degrees, flip) = match value {
+            2 => (0, true),
+            3 => (180, false),
+            4 => (180, true),
+            5 => (90, true),
+            6 => (90, false),
+            7 => (270, true),
+            8 => (270, false),
+            _ => (0, false),
+        };
+        Self { degrees, flip }
+    }
+
+    /// Rotates the result clockwise
+    fn rotate(self, degrees: u16) -> Self {
+        //rotating a flipped image is the same as flipping an image rotated the other way
+        let degrees = if self.flip {
+            self.degrees + 360 - degrees
+        } else {
+            self.degrees + degrees
+        };
+        Self {
+            degrees: degrees % 360,
+            flip: self.flip,
+        }
+    }
+
+    /// Mirrors the result. A vertical flip is a horizontal flip plus half a turn.
+    fn mirror(self, flip: Flip) -> Self {
+        let degrees = match flip {
+            Flip::Horizontal => self.degrees,
+            Flip::Vertical => (self.degrees + 180) % 360,
+        };
+        Self {
+            degrees,
+            flip: !self.flip,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.degrees == 0 && !self.flip
+    }
+
+    /// Size of a `w`x`h` source once oriented
+    pub fn size(&self, w: u32, h: u32) -> (u32, u32) {
+        match self.degrees {
+            90 | 270 => (h, w),
+            _ => (w, h),
+        }
+    }
+
+    pub fn apply(&self, img: DynamicImage) -> DynamicImage {
+        let img = match self.degrees {
+            90 => img.rotate90(),
+            180 => img.rotate180(),
+            270 => img.rotate270(),
+            _ => img,
+        };
+        if self.flip {
+            img.fliph()
+        } else {
+            img
+        }
+    }
+}
+
+/// Reads the EXIF orientation tag of JPEG, PNG, WebP and TIFF containers
+fn exif_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// Target box requested by the client. A zero width or height is derived from the source
+/// aspect ratio. The source is oriented first, then the crop region and aspect ratio are
+/// extracted from it before resizing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub width: u32,
+    pub height: u32,
+    pub fit: Fit,
+    pub crop: Option<Region>,
+    pub aspect: Option<Aspect>,
+    pub gravity: Gravity,
+    /// Apply the EXIF orientation tag
+    pub auto_orient: bool,
+    pub rotate: Option<Rotate>,
+    pub flip: Option<Flip>,
+    /// Resampling filter. Falls back to `Filter::Lanczos3`.
+    pub filter: Option<Filter>,
+    /// Background behind SVGs and the padding added by `Fit::Contain`, transparent by default
+    pub background: Option<Color>,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            fit: Fit::default(),
+            crop: None,
+            aspect: None,
+            gravity: Gravity::default(),
+            auto_orient: true,
+            rotate: None,
+            flip: None,
+            filter: None,
+            background: None,
+        }
+    }
+}
+
+/// Where the source ends up in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// Area of the oriented source that is kept
+    pub region: Region,
+    /// Size the region is resized to
+    pub scaled: (u32, u32),
+    /// Size of the output, after cropping or padding the scaled region
+    pub canvas: (u32, u32),
+}
+
+impl Layout {
+    pub fn is_noop(&self, imgw: u32, imgh: u32) -> bool {
+        self.region == Region::full(imgw, imgh)
+            && self.scaled == (imgw, imgh)
+            && self.canvas == (imgw, imgh)
+    }
+}
+
+impl Geometry {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0
+            && self.height == 0
+            && self.crop.is_none()
+            && self.aspect.is_none()
+            && self.rotate.is_none()
+            && self.flip.is_none()
+            && self.background.is_none()
+    }
+
+    /// Orientation applied to the source before anything else
+    pub fn orientation(&self, data: &[u8]) -> Orientation {
+        let exif = if self.auto_orient {
+            exif_orientation(data).map(Orientation::from_exif)
+        } else {
+            None
+        };
+        let orientation = exif.unwrap_or_default();
+        let orientation = match self.rotate {
+            Some(r) => orientation.rotate(r.degrees()),
+            None => orientation,
+        };
+        match self.flip {
+            Some(f) => orientation.mirror(f),
+            None => orientation,
+        }
+    }
+
+    fn is_boxed(&self) -> bool {
+        self.width != 0 && self.height != 0
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter.unwrap_or_default()
+    }
+
+    /// Entropy gravity needs the pixels of the source
+    pub fn needs_luma(&self) -> bool {
+        self.gravity == Gravity::Entropy
+    }
+
+    /// Computes the layout for a source of `imgw`x`imgh`. Without `luma`, entropy gravity
+    /// falls back to center.
+    pub fn layout(&self, imgw: u32, imgh: u32, luma: Option<&GrayImage>) -> Layout {
+        self.scaled_layout(imgw, imgh, luma, false)
+    }
+
+    /// Layout for vector sources, which are scaled up with every fit mode since they don't
+    /// lose any quality
+    pub fn vector_layout(&self, imgw: u32, imgh: u32) -> Layout {
+        self.scaled_layout(imgw, imgh, None, true)
+    }
+
+    fn scaled_layout(
+        &self,
+        imgw: u32,
+        imgh: u32,
+        luma: Option<&GrayImage>,
+        upscale: bool,
+    ) -> Layout {
+        let mut region = match self.crop {
+            Some(crop) => crop.clamp(imgw, imgh),
+            None => Region::full(imgw, imgh),
+        };
+        if let Some(Aspect(w, h)) = self.aspect {
+            region = self.extract(region, w as f64 / h as f64, luma);
+        }
+        //covering a box is the same as extracting its aspect ratio and resizing
+        if self.fit == Fit::Cover && self.is_boxed() {
+            region = self.extract(region, self.width as f64 / self.height as f64, luma);
+        }
+        let scaled = self.scaled_size(region.width, region.height, upscale);
+        Layout {
+            region,
+            scaled,
+            canvas: self.canvas_size(scaled),
+        }
+    }
+
+    pub fn is_noop(&self, imgw: u32, imgh: u32) -> bool {
+        self.layout(imgw, imgh, None).is_noop(imgw, imgh)
+    }
+
+    /// Largest area of `region` with the given aspect ratio, placed by gravity
+    fn extract(&self, region: Region, ratio: f64, luma: Option<&GrayImage>) -> Region {
+        let (w, h) = (region.width as f64, region.height as f64);
+        let (w, h) = if w / h > ratio {
+            ((h * ratio).round() as u32, region.height)
+        } else {
+            (region.width, (w / ratio).round() as u32)
+        };
+        let (w, h) = (w.clamp(1, region.width), h.clamp(1, region.height));
+        let (x, y) = self.gravity.offset(region, (w, h), luma);
+        Region {
+            x: region.x + x,
+            y: region.y + y,
+            width: w,
+            height: h,
+        }
+    }
+
+    /// Size the source is resized to, before any cropping or padding.
+    fn scaled_size(&self, imgw: u32, imgh: u32, upscale: bool) -> (u32, u32) {
+        let (w, h) = (imgw as f64, imgh as f64);
+        let ratio = match (self.width, self.height) {
+            (0, 0) => return (imgw, imgh),
+            (tw, 0) => tw as f64 / w,
+            (0, th) => th as f64 / h,
+            (tw, th) => match self.fit {
+                Fit::Fill => return (tw, th),
+                Fit::Inside | Fit::Contain => (tw as f64 / w).min(th as f64 / h),
+                Fit::Cover | Fit::Outside => (tw as f64 / w).max(th as f64 / h),
+            },
+        };
+        let ratio = match self.fit {
+            Fit::Inside | Fit::Outside if !upscale => ratio.min(1.0),
+            _ => ratio,
+        };
+        (
+            ((w * ratio).round() as u32).max(1),
+            ((h * ratio).round() as u32).max(1),
+        )
+    }
+
+    /// Size of the final output once the fit mode has been applied.
+    fn canvas_size(&self, scaled: (u32, u32)) -> (u32, u32) {
+        match self.fit {
+            Fit::Cover | Fit::Contain | Fit::Fill if self.is_boxed() => (self.width, self.height),
+            _ => scaled,
+        }
+    }
+}
+
+/// Output formats that can be requested with the `format` query param.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+    Avif,
+    /// H.264, only for videos
+    Mp4,
+    /// Sanitized vector, only for SVGs
+    Svg,
+}
+
+/// Formats picked through the Accept header for still images, in order of preference
+pub const NEGOTIABLE: [Format; 2] = [Format::Avif, Format::Webp];
+
+/// Inputs that browsers can't display, which are always converted to a web format
+const CONVERTIBLE: [&str; 8] = [
+    "image/tiff",
+    "image/bmp",
+    "image/x-ms-bmp",
+    "image/x-icon",
+    "image/vnd.microsoft.icon",
+    "image/x-tga",
+    "image/x-targa",
+    "image/jxl",
+];
+
+/// Inputs that store every pixel as is
+const LOSSLESS: [&str; 10] = [
+    "image/png",
+    "image/gif",
+    "image/svg+xml",
+    "image/bmp",
+    "image/x-ms-bmp",
+    "image/tiff",
+    "image/x-icon",
+    "image/vnd.microsoft.icon",
+    "image/x-tga",
+    "image/x-targa",
+];
+
+impl Format {
+    pub fn from_mime(mime: &Mime) -> Option<Format> {
+        match mime.essence_str() {
+            "image/jpeg" | "image/jpg" => Some(Format::Jpeg),
+            "image/png" => Some(Format::Png),
+            "image/webp" => Some(Format::Webp),
+            "image/gif" => Some(Format::Gif),
+            "image/avif" => Some(Format::Avif),
+            _ => None,
+        }
+    }
+
+    /// Formats an object of the given type can be negotiated to, in order of preference
+    pub fn negotiable(mime: &Mime) -> &'static [Format] {
+        match mime.essence_str() {
+            "image/jpeg" | "image/jpg" | "image/png" => &NEGOTIABLE,
+            //only WebP keeps the animation
+            "image/gif" => &[Format::Webp],
+            m if CONVERTIBLE.contains(&m) => &NEGOTIABLE,
+            _ => &[],
+        }
+    }
+
+    /// Format used for inputs that browsers can't display, when none was requested or
+    /// negotiated
+    pub fn fallback(mime: &Mime) -> Option<Format> {
+        CONVERTIBLE
+            .contains(&mime.essence_str())
+            .then_some(Format::Png)
+    }
+
+    /// Best candidate explicitly listed in an Accept header. Wildcards are ignored,
+    /// since clients that can't decode WebP still send `image/*` or `*/*`.
+    pub fn from_accept(accept: &str, candidates: &[Format]) -> Option<Format> {
+        let accepted: Vec<Mime> = accept
+            .split(',')
+            .filter_map(|m| m.trim().parse::<Mime>().ok())
+            .filter(|m| match m.get_param("q") {
+                Some(q) => matches!(q.as_str().parse::<f32>(), Ok(q) if q > 0.0),
+                None => true,
+            })
+            .collect();
+        candidates.iter().copied().find(|f| {
+            accepted
+                .iter()
+                .any(|m| m.essence_str() == f.mime().essence_str())
+        })
+    }
+
+    pub fn mime(&self) -> Mime {
+        match self {
+            Self::Jpeg => mime::IMAGE_JPEG,
+            Self::Png => mime::IMAGE_PNG,
+            Self::Gif => mime::IMAGE_GIF,
+            Self::Webp => "image/webp".parse().expect("invalid webp mime"),
+            Self::Avif => "image/avif".parse().expect("invalid avif mime"),
+            Self::Mp4 => "video/mp4".parse().expect("invalid mp4 mime"),
+            Self::Svg => mime::IMAGE_SVG,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let format = match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+            Self::Gif => "gif",
+            Self::Avif => "avif",
+            Self::Mp4 => "mp4",
+            Self::Svg => "svg",
+        };
+        write!(out, "{}", format)
+    }
+}
+
+/// Settings handed to the encoders
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+    /// Quality for lossy encoders, from 1 to 100. Each encoder falls back to its own default.
+    pub quality: Option<u8>,
+    /// Encode WebP losslessly
+    pub lossless: bool,
+    /// AVIF encoder speed, from 0 (slowest) to 10 (fastest)
+    pub avif_speed: u8,
+    /// Default AVIF quality, from 0 to 100
+    pub avif_quality: u8,
+    /// Metadata kept in the output. Falls back to `Strip::Exif`.
+    pub strip: Option<Strip>,
+    /// Convert pixels from the embedded ICC profile to sRGB
+    pub srgb: bool,
+    /// Keep palette PNGs indexed. Falls back to true.
+    pub palette: Option<bool>,
+    /// The input stores every pixel as is, so still WebP outputs default to lossless
+    pub lossless_source: bool,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self {
+            quality: None,
+            lossless: false,
+            avif_speed: 6,
+            avif_quality: 80,
+            strip: None,
+            srgb: false,
+            palette: None,
+            lossless_source: false,
+        }
+    }
+}
+
+impl Encoding {
+    /// True when the client asked for specific encoder settings, which means the object has to
+    /// be encoded again even if its size doesn't change.
+    pub fn is_custom(&self) -> bool {
+        self.quality.is_some()
+            || self.lossless
+            || self.strip.is_some()
+            || self.srgb
+            || self.palette.is_some()
+    }
+
+    fn palette(&self) -> bool {
+        self.palette.unwrap_or(true)
+    }
+
+    pub fn strip(&self) -> Strip {
+        self.strip.unwrap_or_default()
+    }
+
+    fn jpeg_quality(&self) -> u8 {
+        self.quality.unwrap_or(75)
+    }
+
+    fn webp_quality(&self) -> f32 {
+        self.quality.unwrap_or(75) as f32
+    }
+
+    /// Still WebP is lossless when requested, or when the input is lossless and no quality was
+    /// requested
+    fn webp_lossless(&self) -> bool {
+        self.lossless || (self.lossless_source && self.quality.is_none())
+    }
+
+    fn avif_quality(&self) -> u8 {
+        self.quality.unwrap_or(self.avif_quality)
+    }
+
+    /// x264 constant rate factor, from 51 (worst) to 0 (lossless)
+    fn x264_crf(&self) -> u8 {
+        match self.quality {
+            Some(q) => 51 - (q.min(100) as u32 * 51 / 100) as u8,
+            None => 23,
+        }
+    }
+}
+
+/// Orients and resizes a decoded image and crops or pads it to the canvas required by the fit
+/// mode.
+fn apply_geometry(
+    img: DynamicImage,
+    orientation: Orientation,
+    geometry: &Geometry,
+) -> Result<DynamicImage> {
+    let img = orientation.apply(img);
+    let luma = geometry.needs_luma().then(|| img.to_luma8());
+    let layout = geometry.layout(img.width(), img.height(), luma.as_ref());
+    apply_layout(img, &layout, geometry)
+}
+
+/// Extracts the layout region from a decoded image, resizes it and crops or pads the result
+/// to the canvas. Padding takes the background color, and is transparent without one.
+fn apply_layout(img: DynamicImage, layout: &Layout, geometry: &Geometry) -> Result<DynamicImage> {
+    let Region {
+        x,
+        y,
+        width,
+        height,
+    } = layout.region;
+    let img = if layout.region == Region::full(img.width(), img.height()) {
+        img
+    } else {
+        img.crop_imm(x, y, width, height)
+    };
+    let ((w, h), (cw, ch)) = (layout.scaled, layout.canvas);
+    let img = if (w, h) == (img.width(), img.height()) {
+        img
+    } else {
+        resize_image(img, w, h, geometry.filter())?
+    };
+    Ok(if (w, h) == (cw, ch) {
+        img
+    } else if w >= cw && h >= ch {
+        img.crop_imm((w - cw) / 2, (h - ch) / 2, cw, ch)
+    } else {
+        let mut canvas = match geometry.background {
+            Some(Color(rgba)) => {
+                DynamicImage::ImageRgba8(RgbaImage::from_pixel(cw, ch, image::Rgba(rgba)))
+            }
+            None => DynamicImage::new_rgba8(cw, ch),
+        };
+        imageops::overlay(
+            &mut canvas,
+            &img,
+            (cw as i64 - w as i64) / 2,
+            (ch as i64 - h as i64) / 2,
+        );
+        canvas
+    })
+}
+
+/// Resizes a decoded image with the same resampler as the raw PNG path, so every format
+/// supports the same filters.
+fn resize_image(img: DynamicImage, w: u32, h: u32, filter: Filter) -> Result<DynamicImage> {
+    let (w1, h1) = (img.width() as usize, img.height() as usize);
+    let (w2, h2) = (w as usize, h as usize);
+    let resize_type = filter.resize_type();
+    let invalid = || anyhow!("invalid buffer size for {w}x{h} px");
+    let img = match img {
+        DynamicImage::ImageLuma8(src) => {
+            let mut dst = vec![0u8; w2 * h2];
+            resize::new(w1, h1, w2, h2, Pixel::Gray8, resize_type)?
+                .resize(src.as_raw().as_gray(), dst.as_gray_mut())?;
+            DynamicImage::ImageLuma8(GrayImage::from_raw(w, h, dst).ok_or_else(invalid)?)
+        }
+        img if !img.color().has_alpha() => {
+            let src = img.into_rgb8();
+            let mut dst = vec![0u8; w2 * h2 * 3];
+            resize::new(w1, h1, w2, h2, Pixel::RGB8, resize_type)?
+                .resize(src.as_raw().as_rgb(), dst.as_rgb_mut())?;
+            DynamicImage::ImageRgb8(RgbImage::from_raw(w, h, dst).ok_or_else(invalid)?)
+        }
+        img => {
+            let src = img.into_rgba8();
+            let mut dst = vec![0u8; w2 * h2 * 4];
+            resize::new(w1, h1, w2, h2, Pixel::RGBA8, resize_type)?
+                .resize(src.as_raw().as_rgba(), dst.as_rgba_mut())?;
+            DynamicImage::ImageRgba8(RgbaImage::from_raw(w, h, dst).ok_or_else(invalid)?)
+        }
+    };
+    Ok(img)
+}
+
+/// Whether the input keeps every pixel as is. WebPs are lossless when they have a VP8L chunk.
+pub fn is_lossless(data: &[u8], mime: &Mime) -> bool {
+    match mime.essence_str() {
+        "image/webp" => matches!(
+            metadata::webp_chunks(data),
+            Ok(chunks) if chunks.iter().any(|(kind, _)| kind == b"VP8L")
+        ),
+        m => LOSSLESS.contains(&m),
+    }
+}
+
+/// Whether the pixels of `data` can be converted to sRGB. Profiles qcms can't read or can't
+/// apply to RGBA pixels, like gray or CMYK ones, have to be kept with the original pixels.
+pub fn has_srgb_transform(data: &[u8]) -> bool {
+    srgb_transform(data).is_some()
+}
+
+/// Transform from the ICC profile embedded in `data` to sRGB, for 8 bit RGBA pixels
+fn srgb_transform(data: &[u8]) -> Option<qcms::Transform> {
+    let icc = Metadata::read(data).icc?;
+    let input = qcms::Profile::new_from_slice(&icc, false)?;
+    let mut srgb = qcms::Profile::new_sRGB();
+    srgb.precache_output_transform();
+    qcms::Transform::new(
+        &input,
+        &srgb,
+        qcms::DataType::RGBA8,
+        qcms::Intent::default(),
+    )
+}
+
+fn color_manage(img: DynamicImage, transform: Option<&qcms::Transform>) -> DynamicImage {
+    match transform {
+        Some(transform) => {
+            let alpha = img.color().has_alpha();
+            let mut buff = img.into_rgba8();
+            transform.apply(&mut buff);
+            if alpha {
+                DynamicImage::ImageRgba8(buff)
+            } else {
+                DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(buff).into_rgb8())
+            }
+        }
+        None => img,
+    }
+}
+
+/// Converts a decoded image to sRGB when the encoding asks for it. Images without a usable
+/// profile are returned as is.
+fn to_srgb(img: DynamicImage, data: &[u8], encoding: &Encoding) -> DynamicImage {
+    if encoding.srgb {
+        color_manage(img, srgb_transform(data).as_ref())
+    } else {
+        img
+    }
+}
+
+/// Offset of the `w`x`h` window with the most detail inside `region`, measured as the entropy
+/// of its luma histogram on a downscaled copy of the region.
+fn entropy_offset(luma: &GrayImage, region: Region, (w, h): (u32, u32)) -> (u32, u32) {
+    let scale = (128.0 / region.width.max(region.height) as f64).min(1.0);
+    let downscale = |v: u32| ((v as f64 * scale).round() as u32).max(1);
+    let area = imageops::crop_imm(luma, region.x, region.y, region.width, region.height);
+    let thumb = imageops::thumbnail(
+        &area.to_image(),
+        downscale(region.width),
+        downscale(region.height),
+    );
+    let (ww, wh) = (
+        downscale(w).min(thumb.width()),
+        downscale(h).min(thumb.height()),
+    );
+    let (mut best, mut offset) = (f64::MIN, (0, 0));
+    for x in 0..=thumb.width() - ww {
+        for y in 0..=thumb.height() - wh {
+            let e = entropy(&thumb, x, y, ww, wh);
+            if e > best {
+                (best, offset) = (e, (x, y));
+            }
+        }
+    }
+    (
+        ((offset.0 as f64 / scale).round() as u32).min(region.width - w),
+        ((offset.1 as f64 / scale).round() as u32).min(region.height - h),
+    )
+}
+
+fn entropy(img: &GrayImage, x: u32, y: u32, w: u32, h: u32) -> f64 {
+    let mut histogram = [0u32; 256];
+    for py in y..y + h {
+        for px in x..x + w {
+            histogram[img.get_pixel(px, py)[0] as usize] += 1;
+        }
+    }
+    let total = (w * h) as f64;
+    histogram
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Luma of a packed 8 bit pixel buffer, used to measure entropy before decoding to an image
+fn raw_luma(src: &[u8], w: u32, h: u32, channels: usize, bytes: usize) -> GrayImage {
+    //the most significant byte is enough for 16 bit samples
+    GrayImage::from_fn(w, h, |x, y| {
+        let i = (y as usize * w as usize + x as usize) * channels * bytes;
+        match channels {
+            1 | 2 => Luma([src[i]]),
+            _ => Luma([((src[i] as u32 * 299
+                + src[i + bytes] as u32 * 587
+                + src[i + bytes * 2] as u32 * 114)
+                / 1000) as u8]),
+        }
+    })
+}
+
+/// Copies `region` out of a packed pixel buffer `w` pixels wide, with `bpp` bytes per pixel
+fn crop_buffer(src: &[u8], w: usize, region: Region, bpp: usize) -> Vec<u8> {
+    let (x, y) = (region.x as usize, region.y as usize);
+    let row_len = region.width as usize * bpp;
+    (y..y + region.height as usize)
+        .flat_map(|row| {
+            let s = (row * w + x) * bpp;
+            src[s..s + row_len].iter().copied()
+        })
+        .collect()
+}
+
+/// Crops or pads a packed pixel buffer of `w`x`h` to `cw`x`ch`, keeping it centered.
+/// Padding is left zeroed, which is transparent for color types with alpha.
+fn fit_buffer(src: &[u8], (w, h): (usize, usize), (cw, ch): (usize, usize), bpp: usize) -> Vec<u8> {
+    let mut dst = vec![0u8; cw * ch * bpp];
+    let (sx, dx) = if w > cw {
+        ((w - cw) / 2, 0)
+    } else {
+        (0, (cw - w) / 2)
+    };
+    let (sy, dy) = if h > ch {
+        ((h - ch) / 2, 0)
+    } else {
+        (0, (ch - h) / 2)
+    };
+    let row_len = w.min(cw) * bpp;
+    for row in 0..h.min(ch) {
+        let s = ((sy + row) * w + sx) * bpp;
+        let d = ((dy + row) * cw + dx) * bpp;
+        dst[d..d + row_len].copy_from_slice(&src[s..s + row_len]);
+    }
+    dst
+}
+
+pub fn resize_webp(
+    data: &[u8],
+    geometry: &Geometry,
+    animated: bool,
+    encoding: &Encoding,
+) -> Result<Vec<u8>> {
     if !animated {
         let img = image::load_from_memory(data)?;
+        let orientation = geometry.orientation(data);
         //early exit
-        if width == img.width() {
+        if orientation.is_identity()
+            && geometry.is_noop(img.width(), img.height())
+            && !encoding.is_custom()
+        {
             return Ok(data.to_vec());
         };
-        let (w, h) = calculate_dimensions(img.width(), img.height(), width);
 
-        let img = img.resize_exact(w, h, FilterType::Lanczos3);
-        let encoder = webp::Encoder::from_image(&img).unwrap();
-        let memory = encoder.encode_lossless();
+        let img = to_srgb(img, data, encoding);
+        let img = apply_geometry(img, orientation, geometry)?;
+        let encoder = webp::Encoder::from_image(&img).map_err(|e| anyhow!("{e}"))?;
+        let memory = if encoding.webp_lossless() {
+            encoder.encode_lossless()
+        } else {
+            encoder.encode(encoding.webp_quality())
+        };
         let bytes = memory.as_bytes();
         Ok(bytes.to_vec())
     } else {
-        let decoder = webp_animation::Decoder::new(data)?;
-        //Get animation data
-        let frames: Vec<_> = decoder.into_iter().collect();
-        let timestamps: Vec<i32> = frames.iter().map(|f| f.timestamp()).collect();
-        let frame = frames.get(0).unwrap();
-        let img = frame.dimensions();
-
+        let start = Instant::now();
+        let mut frames = Vec::new();
+        let mut end = 0;
+        for frame in webp_animation::Decoder::new(data)? {
+            let (w, h) = frame.dimensions();
+            if (frames.len() as u64 + 1) * w as u64 * h as u64 > MAX_ANIMATION_PIXELS {
+                return Err(anyhow!(
+                    "webp of {w}x{h} px with over {} frames is too large",
+                    frames.len()
+                ));
+            }
+            let img = RgbaImage::from_raw(w, h, frame.data().to_vec())
+                .ok_or_else(|| anyhow!("invalid webp frame"))?;
+            //decoded timestamps mark the end of each frame
+            frames.push((img, frame.timestamp() - end));
+            end = frame.timestamp();
+        }
+        let orientation = geometry.orientation(data);
+        let (imgw, imgh) = match frames.first() {
+            Some((img, _)) => orientation.size(img.width(), img.height()),
+            None => return Err(anyhow!("webp without frames")),
+        };
         //early exit
-        if width == img.0 {
+        if orientation.is_identity() && geometry.is_noop(imgw, imgh) && !encoding.is_custom() {
             return Ok(data.to_vec());
         };
 
-        let (w, h) = calculate_dimensions(img.0, img.1, width);
-
-        //init encoder
-        let mut encoder = Encoder::new_with_options(
-            (w, h),
-            EncoderOptions {
-                kmin: 3,
-                kmax: 5,
-                encoding_config: Some(EncodingConfig {
-                    quality: 75.,
-                    encoding_type: EncodingType::Lossy(LossyEncodingConfig {
-                        segments: 2,
-                        alpha_compression: true,
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                }),
+        let frames = to_srgb_frames(frames, data, encoding);
+        let frames = layout_frames(frames, orientation, geometry)?;
+        let (w, h) = frames[0].0.dimensions();
+        let bytes = encode_webp_animation(frames, webp_loop_count(data), encoding);
+        info!(
+            "Resized animated webp to {}x{} px in {}",
+            w,
+            h,
+            Elapsed::from(&start)
+        );
+        bytes
+    }
+}
+
+/// Loop count stored in the ANIM chunk of an animated WebP, after the background color. 0 loops
+/// forever.
+fn webp_loop_count(data: &[u8]) -> i32 {
+    let chunks = metadata::webp_chunks(data).unwrap_or_default();
+    chunks
+        .iter()
+        .find(|(kind, _)| kind == b"ANIM")
+        .and_then(|(_, body)| body.get(4..6))
+        .map_or(0, |b| u16::from_le_bytes([b[0], b[1]]) as i32)
+}
+
+/// Encodes full canvas frames, with their duration in milliseconds, as an animated WebP.
+/// A loop count of 0 loops forever.
+fn encode_webp_animation(
+    frames: Vec<(RgbaImage, i32)>,
+    loop_count: i32,
+    encoding: &Encoding,
+) -> Result<Vec<u8>> {
+    let (w, h) = match frames.first() {
+        Some((img, _)) => img.dimensions(),
+        None => return Err(anyhow!("animation without frames")),
+    };
+    let encoding_type = if encoding.lossless {
+        EncodingType::Lossless
+    } else {
+        EncodingType::Lossy(LossyEncodingConfig {
+            segments: 2,
+            alpha_compression: true,
+            ..Default::default()
+        })
+    };
+    let mut encoder = Encoder::new_with_options(
+        (w, h),
+        EncoderOptions {
+            anim_params: AnimParams { loop_count },
+            kmin: 3,
+            kmax: 5,
+            encoding_config: Some(EncodingConfig {
+                quality: encoding.webp_quality(),
+                encoding_type,
                 ..Default::default()
-            },
-        )?;
+            }),
+            ..Default::default()
+        },
+    )?;
+    let mut timestamp = 0;
+    for (img, duration) in frames {
+        encoder.add_frame(img.as_raw(), timestamp)?;
+        timestamp += duration;
+    }
+    Ok(encoder.finalize(timestamp)?.to_vec())
+}
 
-        for (i, frame) in webp_animation::Decoder::new(data)?.into_iter().enumerate() {
-            let mut buff = Cursor::new(Vec::new());
-            frame.into_image()?.write_to(&mut buff, ImageFormat::WebP)?;
-            let img = image::load_from_memory(&buff.into_inner())?;
-            let mut buff = Cursor::new(Vec::new());
-            img.resize_exact(w, h, FilterType::Lanczos3)
-                .write_to(&mut buff, ImageFormat::WebP)?;
-            let frame = webp_animation::Decoder::new(&buff.into_inner())?
-                .into_iter()
-                .next()
-                .unwrap();
-            let timestamp = timestamps.get(i).unwrap();
-            encoder.add_frame(frame.data(), *timestamp)?;
-        }
-        let final_timestamp = *timestamps.last().unwrap() * frames.len() as i32;
-        let bytes = encoder.finalize(final_timestamp)?;
-        Ok(bytes.to_vec())
+/// ffmpeg filter for the video track of an mp4, with the size of its output
+struct Mp4Filter {
+    filter: String,
+    canvas: (u32, u32),
+    /// Duration of the video, in seconds
+    duration: f32,
+}
+
+/// Size of a video track once the rotation of its display matrix is applied, as ffmpeg does
+/// when decoding it
+pub fn mp4_display_size(track: &mp4::Mp4Track) -> (u32, u32) {
+    let (w, h) = (track.width() as u32, track.height() as u32);
+    let matrix = &track.trak.tkhd.matrix;
+    //quarter turns only keep the off-diagonal terms
+    if matrix.a == 0 && matrix.d == 0 && matrix.b != 0 && matrix.c != 0 {
+        (h, w)
+    } else {
+        (w, h)
     }
 }
 
-pub fn mp4_to_gif(input_path: &str, output_path: &str, width: u32) -> Result<Vec<u8>> {
-    let start = Instant::now();
-    let f = fs::File::open(input_path).unwrap();
-    let mp4 = mp4::read_mp4(f).unwrap();
-    let video_tracks: Vec<_> = mp4
+/// Builds the ffmpeg filter that orients the frames, extracts the region, scales them and then
+/// crops or pads them to the canvas. Fails for mp4s without a video track.
+fn mp4_filter(input_path: &str, geometry: &Geometry) -> Result<Mp4Filter> {
+    let f = fs::File::open(input_path)?;
+    let mp4 = mp4::read_mp4(f)?;
+    let track = mp4
         .tracks()
         .values()
-        .into_iter()
-        .filter(|t| t.track_type().unwrap() == TrackType::Video)
-        .collect();
+        .find(|t| matches!(t.track_type(), Ok(TrackType::Video)))
+        .ok_or_else(|| anyhow!("mp4 without a video track"))?;
 
-    let (w, h) = calculate_dimensions(
-        video_tracks[0].width() as u32,
-        video_tracks[0].height() as u32,
+    let (imgw, imgh) = mp4_display_size(track);
+    let duration = track.duration().as_secs_f32();
+    //ffmpeg already applies the rotation stored in the container, so only the requested
+    //rotate and flip are added
+    let orientation = geometry.orientation(&[]);
+    let (imgw, imgh) = orientation.size(imgw, imgh);
+    let layout = geometry.layout(imgw, imgh, None);
+    let ((sw, sh), (w, h)) = (layout.scaled, layout.canvas);
+    let mut filters = Vec::new();
+    match orientation.degrees {
+        90 => filters.push("transpose=clock".to_string()),
+        180 => filters.push("hflip,vflip".to_string()),
+        270 => filters.push("transpose=cclock".to_string()),
+        _ => {}
+    };
+    if orientation.flip {
+        filters.push("hflip".to_string());
+    }
+    let Region {
+        x,
+        y,
         width,
-    );
-
-    let mut handle = spawn!(./mp4-to-gif.sh ${input_path} ${w} ${h} ${output_path})?;
+        height,
+    } = layout.region;
+    if layout.region != Region::full(imgw, imgh) {
+        filters.push(format!("crop={width}:{height}:{x}:{y}"));
+    }
+    filters.push(format!(
+        "scale={sw}:{sh}:flags={}",
+        geometry.filter().ffmpeg_flags()
+    ));
+    let mut filter = filters.join(",");
+    if sw >= w && sh >= h && (sw, sh) != (w, h) {
+        filter.push_str(&format!(",crop={w}:{h}"));
+    } else if (sw, sh) != (w, h) {
+        filter.push_str(&format!(",pad={w}:{h}:(ow-iw)/2:(oh-ih)/2"));
+        if let Some(color) = geometry.background {
+            filter.push_str(&format!(":color=0x{color}"));
+        }
+    }
+    Ok(Mp4Filter {
+        filter,
+        canvas: (w, h),
+        duration,
+    })
+}
 
-    if handle.wait().is_err() {
-        error!("Unable to convert mp4 to gif with run_cmd.. Falling back to original file");
-        read_from_file(input_path)
-    } else {
-        info!(
-            "Converted mp4 to gif and resized to {} px in {}",
-            width,
-            Elapsed::from(&start)
+/// Extracts the frames of an mp4 with ffmpeg into a temp dir under `tmp_path`, capped in
+/// number and size by the video config. The temp dir is removed even if the extraction fails.
+fn mp4_frames(
+    input_path: &str,
+    tmp_path: &str,
+    geometry: &Geometry,
+    video: &VideoConfig,
+) -> Result<Vec<RgbaImage>> {
+    let Mp4Filter {
+        filter,
+        canvas: (w, h),
+        ..
+    } = mp4_filter(input_path, geometry)?;
+    let mut filter = format!("fps={},{filter}", video.fps.max(1));
+    //frames are decoded before encoding, so a 4K clip without a width would take gigabytes
+    let edge = w.max(h);
+    if edge > video.max_edge {
+        let ratio = video.max_edge as f64 / edge as f64;
+        let (w, h) = (
+            ((w as f64 * ratio).round() as u32).max(1),
+            ((h as f64 * ratio).round() as u32).max(1),
         );
-        read_from_file(output_path)
+        filter.push_str(&format!(
+            ",scale={w}:{h}:flags={}",
+            geometry.filter().ffmpeg_flags()
+        ));
     }
+    let duration = video.max_duration.to_string();
+    let max_frames = video.max_frames.to_string();
+    let dir = TempDir::new(tmp_path)?;
+    let pattern = format!("{}/frame-%05d.png", dir.path);
+
+    run_cmd!(ffmpeg -loglevel error -nostdin -t ${duration} -i ${input_path} -an -vf ${filter} -frames:v ${max_frames} ${pattern})?;
+
+    let mut paths = fs::read_dir(&dir.path)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+    paths
+        .iter()
+        .map(|p| Ok(image::open(p)?.into_rgba8()))
+        .collect()
 }
-pub fn resize_gif(input_path: &str, output_path: &str, width: u32) -> Result<Vec<u8>> {
+
+/// Converts an mp4 to an endlessly looping GIF
+pub fn mp4_to_gif(
+    input_path: &str,
+    tmp_path: &str,
+    geometry: &Geometry,
+    video: &VideoConfig,
+) -> Result<Vec<u8>> {
     let start = Instant::now();
-    let file = fs::File::open(input_path)?;
-    let og_gif = read_from_file(input_path);
-    let mut reader = {
-        let mut options = gif::DecodeOptions::new();
-        options.set_color_output(gif::ColorOutput::Indexed);
-        options.set_memory_limit(::gif::MemoryLimit(8000 * 8000));
-        options.allow_unknown_blocks(true);
-        match options.read_info(&file) {
-            Ok(r) => r,
-            Err(e) => {
-                error!("Error decoding gif: {e}");
-                return og_gif;
+    let frames = mp4_frames(input_path, tmp_path, geometry, video)?;
+    let count = frames.len();
+    //gif delays are set in hundredths of a second
+    let delay = (100.0 / video.fps.max(1) as f32).round().max(2.0) as u16;
+    let frames = frames.into_iter().map(|f| (f, delay)).collect();
+    let gif = encode_gif(frames, gif::Repeat::Infinite)?;
+    info!(
+        "Converted mp4 to gif with {} frames in {}",
+        count,
+        Elapsed::from(&start)
+    );
+    Ok(gif)
+}
+
+/// Converts an mp4 to an endlessly looping animated WebP
+pub fn mp4_to_webp(
+    input_path: &str,
+    tmp_path: &str,
+    geometry: &Geometry,
+    video: &VideoConfig,
+    encoding: &Encoding,
+) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let frames = mp4_frames(input_path, tmp_path, geometry, video)?;
+    let count = frames.len();
+    let duration = (1000.0 / video.fps.max(1) as f32).round() as i32;
+    let frames = frames.into_iter().map(|f| (f, duration)).collect();
+    let webp = encode_webp_animation(frames, 0, encoding)?;
+    info!(
+        "Converted mp4 to webp with {} frames in {}",
+        count,
+        Elapsed::from(&start)
+    );
+    Ok(webp)
+}
+
+/// Re-encodes the video track of an mp4 to H.264 with the requested geometry. Audio is kept
+/// as is and the duration is capped by the video config.
+pub fn resize_mp4(
+    input_path: &str,
+    tmp_path: &str,
+    geometry: &Geometry,
+    video: &VideoConfig,
+    encoding: &Encoding,
+) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let Mp4Filter {
+        filter,
+        canvas: (w, h),
+        ..
+    } = mp4_filter(input_path, geometry)?;
+    //yuv420p needs even dimensions
+    let filter = format!("{filter},crop=trunc(iw/2)*2:trunc(ih/2)*2");
+    let duration = video.max_duration.to_string();
+    let crf = encoding.x264_crf().to_string();
+    let dir = TempDir::new(tmp_path)?;
+    let output = format!("{}/video.mp4", dir.path);
+
+    run_cmd!(ffmpeg -loglevel error -nostdin -t ${duration} -i ${input_path} -vf ${filter} -c:v libx264 -preset veryfast -crf ${crf} -pix_fmt yuv420p -c:a copy -movflags +faststart ${output})?;
+
+    let mp4 = read_from_file(&output)?;
+    info!("Resized mp4 to {}x{} px in {}", w, h, Elapsed::from(&start));
+    Ok(mp4)
+}
+
+/// Extracts a single frame of an mp4 with ffmpeg and encodes it in the requested format
+pub fn mp4_poster(
+    input_path: &str,
+    tmp_path: &str,
+    geometry: &Geometry,
+    frame: Frame,
+    format: Format,
+    encoding: &Encoding,
+) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let Mp4Filter {
+        filter,
+        canvas: (w, h),
+        duration,
+    } = mp4_filter(input_path, geometry)?;
+    let seconds = frame.seconds(duration).to_string();
+    let dir = TempDir::new(tmp_path)?;
+    let output = format!("{}/poster.png", dir.path);
+
+    run_cmd!(ffmpeg -loglevel error -nostdin -ss ${seconds} -i ${input_path} -an -vf ${filter} -frames:v 1 ${output})?;
+
+    let png = read_from_file(&output)?;
+    let poster = match format {
+        Format::Png => png,
+        _ => transcode(&png, &Geometry::default(), format, encoding)?,
+    };
+    info!(
+        "Extracted {} frame of mp4 as {} at {}x{} px in {}",
+        frame,
+        format,
+        w,
+        h,
+        Elapsed::from(&start)
+    );
+    Ok(poster)
+}
+/// Decoded pixels kept for all the frames of an animation, about 256 MB of RGBA
+const MAX_ANIMATION_PIXELS: u64 = 64_000_000;
+
+/// Frames of a GIF composited on its logical screen, with their delay in hundredths of a second
+struct GifAnimation {
+    frames: Vec<(RgbaImage, u16)>,
+    repeat: gif::Repeat,
+}
+
+/// Decodes every frame of a GIF, applying local palettes, transparency and disposal methods.
+/// Fails once the frames would take more than `MAX_ANIMATION_PIXELS`.
+fn decode_gif(data: &[u8]) -> Result<GifAnimation> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    options.set_memory_limit(gif::MemoryLimit(8000 * 8000));
+    options.allow_unknown_blocks(true);
+    let mut decoder = options.read_info(data)?;
+    let (w, h) = (decoder.width() as u32, decoder.height() as u32);
+    let mut canvas = RgbaImage::new(w, h);
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame()? {
+        if (frames.len() as u64 + 1) * w as u64 * h as u64 > MAX_ANIMATION_PIXELS {
+            return Err(anyhow!(
+                "gif of {w}x{h} px with over {} frames is too large",
+                frames.len()
+            ));
+        }
+        let previous = match frame.dispose {
+            gif::DisposalMethod::Previous => Some(canvas.clone()),
+            _ => None,
+        };
+        let (left, top) = (frame.left as u32, frame.top as u32);
+        let (fw, fh) = (frame.width as u32, frame.height as u32);
+        let pixels = RgbaImage::from_raw(fw, fh, frame.buffer.to_vec())
+            .ok_or_else(|| anyhow!("invalid gif frame of {fw}x{fh} px"))?;
+        //transparent pixels let the previous frames show through
+        for (x, y, pixel) in pixels.enumerate_pixels() {
+            if pixel[3] != 0 && left + x < w && top + y < h {
+                canvas.put_pixel(left + x, top + y, *pixel);
             }
         }
-    };
+        frames.push((canvas.clone(), frame.delay));
+        match frame.dispose {
+            gif::DisposalMethod::Background => {
+                for y in top..(top + fh).min(h) {
+                    for x in left..(left + fw).min(w) {
+                        canvas.put_pixel(x, y, image::Rgba([0, 0, 0, 0]));
+                    }
+                }
+            }
+            gif::DisposalMethod::Previous => canvas = previous.unwrap_or(canvas),
+            _ => {}
+        }
+    }
+    Ok(GifAnimation {
+        frames,
+        repeat: gif_repeat(data),
+    })
+}
+
+/// Loop count from the NETSCAPE2.0 application extension. GIFs without it play once.
+fn gif_repeat(data: &[u8]) -> gif::Repeat {
+    data.windows(11)
+        .position(|w| w == b"NETSCAPE2.0")
+        .and_then(|i| data.get(i + 11..i + 15))
+        .filter(|b| b[0] == 3 && b[1] == 1)
+        .map(|b| match u16::from_le_bytes([b[2], b[3]]) {
+            0 => gif::Repeat::Infinite,
+            n => gif::Repeat::Finite(n),
+        })
+        .unwrap_or(gif::Repeat::Finite(0))
+}
+
+/// Pixels written for one frame of a GIF
+struct GifPatch {
+    left: u16,
+    top: u16,
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+    delay: u16,
+    /// Clear the canvas once the frame was shown, instead of keeping it
+    clear: bool,
+}
 
-    let (w, h) = match reader.read_next_frame() {
-        Ok(Some(frame)) => (frame.width, frame.height),
-        Ok(None) => (width as u16, width as u16),
-        Err(error) => {
-            error!("error decoding gif frame - unable to detect width and height - forcing resize to square: {}", error);
-            (width as u16, width as u16)
+impl GifPatch {
+    fn full(img: &RgbaImage, delay: u16) -> Self {
+        Self {
+            left: 0,
+            top: 0,
+            width: img.width() as u16,
+            height: img.height() as u16,
+            pixels: img.as_raw().clone(),
+            delay,
+            clear: false,
+        }
+    }
+
+    /// Smallest rectangle of `img` that differs from `previous`, with the pixels that didn't
+    /// change left transparent. None when both frames look the same.
+    fn diff(previous: &RgbaImage, img: &RgbaImage, delay: u16) -> Option<Self> {
+        let same = |a: &image::Rgba<u8>, b: &image::Rgba<u8>| a == b || (a[3] == 0 && b[3] == 0);
+        let (mut x0, mut y0, mut x1, mut y1) = (u32::MAX, u32::MAX, 0, 0);
+        for (x, y, pixel) in img.enumerate_pixels() {
+            if !same(pixel, previous.get_pixel(x, y)) {
+                x0 = x0.min(x);
+                y0 = y0.min(y);
+                x1 = x1.max(x);
+                y1 = y1.max(y);
+            }
+        }
+        if x0 == u32::MAX {
+            return None;
+        }
+        let mut pixels = Vec::with_capacity(((x1 - x0 + 1) * (y1 - y0 + 1) * 4) as usize);
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let pixel = img.get_pixel(x, y);
+                if same(pixel, previous.get_pixel(x, y)) {
+                    pixels.extend_from_slice(&[0, 0, 0, 0])
+                } else {
+                    pixels.extend_from_slice(&pixel.0)
+                }
+            }
+        }
+        Some(Self {
+            left: x0 as u16,
+            top: y0 as u16,
+            width: (x1 - x0 + 1) as u16,
+            height: (y1 - y0 + 1) as u16,
+            pixels,
+            delay,
+            clear: false,
+        })
+    }
+
+    fn write<W: std::io::Write>(mut self, encoder: &mut gif::Encoder<W>) -> Result<()> {
+        let mut frame = gif::Frame::from_rgba_speed(self.width, self.height, &mut self.pixels, 10);
+        frame.left = self.left;
+        frame.top = self.top;
+        frame.delay = self.delay;
+        frame.dispose = if self.clear {
+            gif::DisposalMethod::Background
+        } else {
+            gif::DisposalMethod::Keep
+        };
+        encoder.write_frame(&frame)?;
+        Ok(())
+    }
+}
+
+/// Encodes full canvas frames, each quantized to its own local palette. Only the rectangle
+/// that changed since the previous frame is written, and repeated frames extend the delay of
+/// the previous one.
+fn encode_gif(frames: Vec<(RgbaImage, u16)>, repeat: gif::Repeat) -> Result<Vec<u8>> {
+    let (w, h) = match frames.first() {
+        Some((img, _)) => (img.width() as u16, img.height() as u16),
+        None => return Err(anyhow!("gif without frames")),
+    };
+    let mut buff = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut buff, w, h, &[])?;
+        encoder.set_repeat(repeat)?;
+        //frames are written one behind, so they can still be merged or made to clear the canvas
+        let mut previous: Option<RgbaImage> = None;
+        let mut pending: Option<GifPatch> = None;
+        for (img, delay) in frames {
+            let patch = match (&previous, pending.as_mut()) {
+                (Some(prev), Some(last)) => {
+                    //pixels turning transparent can only be drawn over a cleared canvas
+                    let reveals = img
+                        .pixels()
+                        .zip(prev.pixels())
+                        .any(|(p, q)| p[3] == 0 && q[3] != 0);
+                    if reveals {
+                        *last = GifPatch::full(prev, last.delay);
+                        last.clear = true;
+                        GifPatch::full(&img, delay)
+                    } else {
+                        match GifPatch::diff(prev, &img, delay) {
+                            Some(patch) => patch,
+                            None => {
+                                last.delay = last.delay.saturating_add(delay);
+                                continue;
+                            }
+                        }
+                    }
+                }
+                _ => GifPatch::full(&img, delay),
+            };
+            if let Some(last) = pending.replace(patch) {
+                last.write(&mut encoder)?;
+            }
+            previous = Some(img);
         }
+        if let Some(last) = pending {
+            last.write(&mut encoder)?;
+        }
+    }
+    Ok(buff)
+}
+
+/// Converts the frames of an animation to sRGB when the encoding asks for it
+fn to_srgb_frames<T: Send>(
+    frames: Vec<(RgbaImage, T)>,
+    data: &[u8],
+    encoding: &Encoding,
+) -> Vec<(RgbaImage, T)> {
+    match encoding.srgb.then(|| srgb_transform(data)).flatten() {
+        Some(srgb) => frames
+            .into_par_iter()
+            .map(|(img, delay)| {
+                let img = color_manage(DynamicImage::ImageRgba8(img), Some(&srgb));
+                (img.into_rgba8(), delay)
+            })
+            .collect(),
+        None => frames,
+    }
+}
+
+/// Orients and resizes every frame with the layout picked from the first one, so the region
+/// doesn't move between frames
+fn layout_frames<T: Send>(
+    frames: Vec<(RgbaImage, T)>,
+    orientation: Orientation,
+    geometry: &Geometry,
+) -> Result<Vec<(RgbaImage, T)>> {
+    let first = match frames.first() {
+        Some((img, _)) => orientation.apply(DynamicImage::ImageRgba8(img.clone())),
+        None => return Err(anyhow!("animation without frames")),
     };
+    let luma = geometry.needs_luma().then(|| first.to_luma8());
+    let layout = geometry.layout(first.width(), first.height(), luma.as_ref());
+    frames
+        .into_par_iter()
+        .map(|(img, delay)| {
+            let img = orientation.apply(DynamicImage::ImageRgba8(img));
+            Ok((apply_layout(img, &layout, geometry)?.into_rgba8(), delay))
+        })
+        .collect()
+}
 
+pub fn resize_gif(data: &[u8], geometry: &Geometry) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let animation = decode_gif(data)?;
+    let first = match animation.frames.first() {
+        Some((img, _)) => img,
+        None => return Err(anyhow!("gif without frames")),
+    };
+    let orientation = geometry.orientation(data);
     //early exit
-    if width == w as u32 {
-        return og_gif;
+    if orientation.is_identity() && geometry.is_noop(first.width(), first.height()) {
+        return Ok(data.to_vec());
     };
-    let (w2, h2) = calculate_dimensions(w as u32, h as u32, width);
+    let frames = layout_frames(animation.frames, orientation, geometry)?;
+    let (w, h) = frames[0].0.dimensions();
+    let bytes = encode_gif(frames, animation.repeat);
+    info!("Resized gif to {}x{} px in {}", w, h, Elapsed::from(&start));
+    bytes
+}
+
+/// Converts a GIF to an animated WebP, keeping the frame delays and loop count
+pub fn gif_to_webp(data: &[u8], geometry: &Geometry, encoding: &Encoding) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let animation = decode_gif(data)?;
+    let frames = layout_frames(animation.frames, geometry.orientation(data), geometry)?;
+    let (w, h) = frames[0].0.dimensions();
+    //browsers play delays under 20ms at 100ms
+    let frames = frames
+        .into_iter()
+        .map(|(img, delay)| match delay {
+            0 | 1 => (img, 100),
+            d => (img, d as i32 * 10),
+        })
+        .collect();
+    //gif counts the repetitions after the first play, webp counts every play
+    let loop_count = match animation.repeat {
+        gif::Repeat::Infinite => 0,
+        gif::Repeat::Finite(n) => n as i32 + 1,
+    };
+    let bytes = encode_webp_animation(frames, loop_count, encoding);
+    info!(
+        "Converted gif to webp at {}x{} px in {}",
+        w,
+        h,
+        Elapsed::from(&start)
+    );
+    bytes
+}
+
+/// Whether a PNG is animated, which is signaled by an acTL chunk before the image data
+pub fn is_apng(data: &[u8]) -> bool {
+    data.starts_with(PNG_SIGNATURE)
+        && metadata::png_chunks(data)
+            .unwrap_or_default()
+            .iter()
+            .take_while(|(kind, _)| kind != b"IDAT")
+            .any(|(kind, _)| kind == b"acTL")
+}
+
+/// Frames of an APNG composited on the canvas according to their blend and dispose ops, so
+/// every frame can be resized on its own. Delays are in milliseconds.
+struct ApngAnimation {
+    frames: Vec<(RgbaImage, i32)>,
+    /// 0 plays forever
+    plays: u32,
+}
+
+/// Fails once the frames would take more than `MAX_ANIMATION_PIXELS`.
+fn decode_apng(data: &[u8]) -> Result<ApngAnimation> {
+    let mut frames = Vec::new();
+    for frame in PngDecoder::new(Cursor::new(data))?.apng().into_frames() {
+        let frame = frame?;
+        let (w, h) = frame.buffer().dimensions();
+        if (frames.len() as u64 + 1) * w as u64 * h as u64 > MAX_ANIMATION_PIXELS {
+            return Err(anyhow!(
+                "apng of {w}x{h} px with over {} frames is too large",
+                frames.len()
+            ));
+        }
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay = (numer as f64 / denom.max(1) as f64).round() as i32;
+        frames.push((frame.into_buffer(), delay));
+    }
+    let plays = metadata::png_chunks(data)?
+        .into_iter()
+        .find(|(kind, _)| kind == b"acTL")
+        .and_then(|(_, body)| body.get(4..8))
+        .map_or(0, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    Ok(ApngAnimation { frames, plays })
+}
 
-    let mut handle = spawn!(gifsicle ${input_path} -o ${output_path} --resize ${w2}x${h2})?;
-    if handle.wait().is_err() {
-        error!(
-            "Unable to convert gif from path {} with run_cmd.. Falling back to original image",
-            input_path
+/// Encodes full canvas frames, with their delay in milliseconds, as an APNG. Every frame
+/// replaces the previous one, so no blending or disposal is needed.
+fn encode_apng(frames: Vec<(RgbaImage, i32)>, plays: u32) -> Result<Vec<u8>> {
+    let (w, h) = match frames.first() {
+        Some((img, _)) => img.dimensions(),
+        None => return Err(anyhow!("apng without frames")),
+    };
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut sequence: u32 = 0;
+    let count = frames.len() as u32;
+    for (i, (img, delay)) in frames.into_iter().enumerate() {
+        //each frame is encoded as a regular png to reuse its compressed image data
+        let mut png = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png, w, h);
+            encoder.set_color(ColorType::RGBA);
+            encoder.set_depth(BitDepth::Eight);
+            encoder.write_header()?.write_image_data(img.as_raw())?;
+        }
+        let chunks = metadata::png_chunks(&png)?;
+        if i == 0 {
+            let ihdr = chunks
+                .iter()
+                .find(|(kind, _)| kind == b"IHDR")
+                .ok_or_else(|| anyhow!("png without IHDR chunk"))?;
+            metadata::push_png_chunk(&mut out, b"IHDR", &[ihdr.1]);
+            metadata::push_png_chunk(
+                &mut out,
+                b"acTL",
+                &[count.to_be_bytes().as_slice(), &plays.to_be_bytes()],
+            );
+        }
+        //delay in thousandths of a second, dispose op none and blend op source
+        let delay = delay.clamp(0, u16::MAX as i32) as u16;
+        metadata::push_png_chunk(
+            &mut out,
+            b"fcTL",
+            &[
+                sequence.to_be_bytes().as_slice(),
+                &w.to_be_bytes(),
+                &h.to_be_bytes(),
+                &[0; 8],
+                &delay.to_be_bytes(),
+                &1000u16.to_be_bytes(),
+                &[0, 0],
+            ],
         );
-        og_gif
-    } else {
-        info!("Resized gif to {} px in {}", width, Elapsed::from(&start));
-        read_from_file(output_path)
+        sequence += 1;
+        for (_, body) in chunks.iter().filter(|(kind, _)| kind == b"IDAT") {
+            if i == 0 {
+                metadata::push_png_chunk(&mut out, b"IDAT", &[*body]);
+            } else {
+                let number = sequence.to_be_bytes();
+                metadata::push_png_chunk(&mut out, b"fdAT", &[number.as_slice(), *body]);
+                sequence += 1;
+            }
+        }
     }
+    metadata::push_png_chunk(&mut out, b"IEND", &[]);
+    Ok(out)
+}
+
+/// Resizes every frame of an APNG, keeping their delays and the play count
+pub fn resize_apng(data: &[u8], geometry: &Geometry, encoding: &Encoding) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let (imgw, imgh) = Reader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_dimensions()?;
+    let orientation = geometry.orientation(data);
+    //early exit
+    if orientation.is_identity() && geometry.is_noop(imgw, imgh) && !encoding.is_custom() {
+        return Ok(data.to_vec());
+    };
+    let (frames, plays) = apng_frames(data, geometry, encoding)?;
+    let (w, h) = frames[0].0.dimensions();
+    let bytes = encode_apng(frames, plays);
+    info!(
+        "Resized apng to {}x{} px in {}",
+        w,
+        h,
+        Elapsed::from(&start)
+    );
+    bytes
 }
 
-pub fn svg_to_png(data: &[u8]) -> Result<Vec<u8>> {
+/// Converts an APNG to an animated WebP, keeping the frame delays and the play count
+pub fn apng_to_webp(data: &[u8], geometry: &Geometry, encoding: &Encoding) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let (frames, plays) = apng_frames(data, geometry, encoding)?;
+    let (w, h) = frames[0].0.dimensions();
+    let bytes = encode_webp_animation(frames, plays as i32, encoding);
+    info!(
+        "Converted apng to webp at {}x{} px in {}",
+        w,
+        h,
+        Elapsed::from(&start)
+    );
+    bytes
+}
+
+/// Decoded, color managed and resized frames of an APNG, with its play count
+fn apng_frames(
+    data: &[u8],
+    geometry: &Geometry,
+    encoding: &Encoding,
+) -> Result<(Vec<(RgbaImage, i32)>, u32)> {
+    let ApngAnimation { frames, plays } = decode_apng(data)?;
+    let frames = to_srgb_frames(frames, data, encoding);
+    let frames = layout_frames(frames, geometry.orientation(data), geometry)?;
+    Ok((frames, plays))
+}
+
+/// Rasterizes an SVG straight at the size required by the geometry and encodes it in the
+/// requested format
+pub fn render_svg(
+    data: &[u8],
+    geometry: &Geometry,
+    format: Format,
+    encoding: &Encoding,
+) -> Result<Vec<u8>> {
+    let start = Instant::now();
     let mut opt = usvg::Options::default();
     opt.fontdb.load_system_fonts();
     let rtree = usvg::Tree::from_data(data, &opt.to_ref())?;
-    let pixmap_size = rtree.svg_node().size.to_screen_size();
-    let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()).unwrap();
-
-    resvg::render(
-        &rtree,
-        usvg::FitTo::Original,
-        tiny_skia::Transform::default(),
-        pixmap.as_mut(),
+    let size = rtree.svg_node().size;
+    let (w, h) = (size.width() as f32, size.height() as f32);
+    //svgs have no EXIF, only the requested rotate and flip apply
+    let orientation = geometry.orientation(&[]);
+    let (imgw, imgh) = orientation.size(size.width().round() as u32, size.height().round() as u32);
+    let layout = geometry.vector_layout(imgw.max(1), imgh.max(1));
+    let ((sw, sh), (cw, ch)) = (layout.scaled, layout.canvas);
+
+    //orientation as an affine transform of the svg coordinates
+    let (a, b, c, d, e, f) = match orientation.degrees {
+        90 => (0.0, 1.0, -1.0, 0.0, h, 0.0),
+        180 => (-1.0, 0.0, 0.0, -1.0, w, h),
+        270 => (0.0, -1.0, 1.0, 0.0, 0.0, w),
+        _ => (1.0, 0.0, 0.0, 1.0, 0.0, 0.0),
+    };
+    let oriented_width = if orientation.degrees % 180 == 0 { w } else { h };
+    let (a, c, e) = if orientation.flip {
+        (-a, -c, oriented_width - e)
+    } else {
+        (a, c, e)
+    };
+    //the region is scaled and centered on the canvas, which crops or pads it
+    let Region {
+        x,
+        y,
+        width,
+        height,
+    } = layout.region;
+    let (sx, sy) = (sw as f32 / width as f32, sh as f32 / height as f32);
+    let (ox, oy) = ((cw as f32 - sw as f32) / 2.0, (ch as f32 - sh as f32) / 2.0);
+    let transform = tiny_skia::Transform::from_row(
+        sx * a,
+        sy * b,
+        sx * c,
+        sy * d,
+        sx * (e - x as f32) + ox,
+        sy * (f - y as f32) + oy,
     )
-    .unwrap();
+    .ok_or_else(|| anyhow!("invalid svg transform"))?;
 
-    Ok(pixmap.encode_png()?)
+    let mut pixmap =
+        tiny_skia::Pixmap::new(cw, ch).ok_or_else(|| anyhow!("invalid svg size {cw}x{ch}"))?;
+    if let Some(Color([r, g, b, a])) = geometry.background {
+        pixmap.fill(tiny_skia::Color::from_rgba8(r, g, b, a));
+    }
+    resvg::render(&rtree, usvg::FitTo::Original, transform, pixmap.as_mut())
+        .ok_or_else(|| anyhow!("unable to render svg"))?;
+    let png = pixmap.encode_png()?;
+    let bytes = match format {
+        Format::Png => png,
+        _ => transcode(&png, &Geometry::default(), format, encoding)?,
+    };
+    info!(
+        "Rendered svg to {} at {}x{} px in {}",
+        format,
+        cw,
+        ch,
+        Elapsed::from(&start)
+    );
+    Ok(bytes)
 }
 
 pub fn is_webp_animated(data: &[u8]) -> bool {
+    //too short to hold the ANIM chunk header
+    if data.len() < 34 {
+        return false;
+    }
     let (riff, webp, vp8x, anim);
     let buff = Cursor::new(data);
     //Read 4 bytes -> 'RIFF'
@@ -189,84 +1972,345 @@ pub fn is_webp_animated(data: &[u8]) -> bool {
 
     riff && webp && anim && vp8x
 }
-pub fn resize_static(data: &[u8], width: u32, format: ImageFormat) -> Result<Vec<u8>> {
+pub fn resize_static(
+    data: &[u8],
+    geometry: &Geometry,
+    format: ImageFormat,
+    encoding: &Encoding,
+) -> Result<Vec<u8>> {
     let start = Instant::now();
 
     let bytes = match format {
-        ImageFormat::Png => resize_png(data, width),
-        ImageFormat::WebP => resize_webp(data, width, is_webp_animated(data)),
+        ImageFormat::Png => resize_png(data, geometry, encoding),
+        ImageFormat::WebP => resize_webp(data, geometry, is_webp_animated(data), encoding),
         _ => {
             let mut buff = Cursor::new(Vec::new());
             let reader = Reader::with_format(Cursor::new(data), format);
             let img = reader.decode()?;
+            let orientation = geometry.orientation(data);
             //early exit
-            if width == img.width() {
+            if orientation.is_identity()
+                && geometry.is_noop(img.width(), img.height())
+                && !encoding.is_custom()
+            {
                 return Ok(data.to_vec());
             };
-            let (w, h) = calculate_dimensions(img.width(), img.height(), width);
+            let output = match format {
+                ImageFormat::Jpeg => ImageOutputFormat::Jpeg(encoding.jpeg_quality()),
+                f => f.into(),
+            };
 
-            img.thumbnail(w, h).write_to(&mut buff, format)?;
+            let img = to_srgb(img, data, encoding);
+            apply_geometry(img, orientation, geometry)?.write_to(&mut buff, output)?;
             Ok(buff.into_inner())
         }
     };
-    info!("Resized to {} px in {}", width, Elapsed::from(&start));
+    info!(
+        "Resized to {}x{} px in {}",
+        geometry.width,
+        geometry.height,
+        Elapsed::from(&start)
+    );
     bytes
 }
 
-fn calculate_dimensions(imgw: u32, imgh: u32, width: u32) -> (u32, u32) {
-    if imgw > width && imgw != imgh {
-        ((imgw / (imgw / width)), (imgh / (imgw / width)))
-    } else if imgh > width && imgw != imgh {
-        ((imgw / (imgh / width)), (imgh / (imgh / width)))
-    } else if imgh == imgw {
-        (width, width)
-    } else {
-        (imgw, imgh)
+/// Decodes a still image, applies the geometry and encodes it in a different format.
+/// Animated inputs are reduced to their first frame.
+pub fn transcode(
+    data: &[u8],
+    geometry: &Geometry,
+    format: Format,
+    encoding: &Encoding,
+) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let img = to_srgb(decode(data)?, data, encoding);
+    let img = apply_geometry(img, geometry.orientation(data), geometry)?;
+    let bytes = encode(img, format, encoding);
+    info!("Converted to {} in {}", format, Elapsed::from(&start));
+    bytes
+}
+
+/// Decodes the first frame of an image and makes it upright. SVGs are rasterized at their
+/// intrinsic size.
+pub fn decode_upright(data: &[u8], mime: &Mime) -> Result<DynamicImage> {
+    let img = match mime.essence_str() {
+        "image/svg+xml" => {
+            let png = render_svg(
+                data,
+                &Geometry::default(),
+                Format::Png,
+                &Encoding::default(),
+            )?;
+            decode(&png)?
+        }
+        _ => decode(data)?,
+    };
+    Ok(Geometry::default().orientation(data).apply(img))
+}
+
+const JXL_CONTAINER: &[u8] = b"\0\0\0\x0CJXL \r\n\x87\n";
+
+/// Whether the data is a bare JPEG XL codestream or an ISOBMFF container
+pub fn is_jxl(data: &[u8]) -> bool {
+    data.starts_with(&[0xFF, 0x0A]) || data.starts_with(JXL_CONTAINER)
+}
+
+/// Decodes any still input. The image crate guesses the format from the content, except for
+/// JPEG XL which goes through jxl-oxide. AVIF needs the `avif-decoder` feature.
+fn decode(data: &[u8]) -> Result<DynamicImage> {
+    if !is_jxl(data) {
+        return Ok(image::load_from_memory(data)?);
     }
+    let image = jxl_oxide::JxlImage::builder().read(Cursor::new(data))?;
+    let render = image.render_frame(0)?;
+    let buffer = render.image_all_channels();
+    let (w, h) = (buffer.width() as u32, buffer.height() as u32);
+    //samples are floats from 0 to 1
+    let pixels: Vec<u8> = buffer
+        .buf()
+        .iter()
+        .map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+        .collect();
+    let invalid = || anyhow!("invalid jpeg xl buffer for {w}x{h} px");
+    Ok(match buffer.channels() {
+        1 => DynamicImage::ImageLuma8(GrayImage::from_raw(w, h, pixels).ok_or_else(invalid)?),
+        2 => DynamicImage::ImageLumaA8(
+            image::GrayAlphaImage::from_raw(w, h, pixels).ok_or_else(invalid)?,
+        ),
+        3 => DynamicImage::ImageRgb8(RgbImage::from_raw(w, h, pixels).ok_or_else(invalid)?),
+        4 => DynamicImage::ImageRgba8(RgbaImage::from_raw(w, h, pixels).ok_or_else(invalid)?),
+        c => return Err(anyhow!("unsupported jpeg xl with {c} channels")),
+    })
 }
-pub fn resize_png(data: &[u8], width: u32) -> Result<Vec<u8>> {
+
+fn encode(img: DynamicImage, format: Format, encoding: &Encoding) -> Result<Vec<u8>> {
+    let mut buff = Cursor::new(Vec::new());
+    //most encoders only take 8 bit rgb(a) buffers
+    let img = if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(img.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    };
+    match format {
+        Format::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_to(&mut buff, ImageOutputFormat::Jpeg(encoding.jpeg_quality()))?,
+        Format::Png => img.write_to(&mut buff, ImageFormat::Png)?,
+        Format::Gif => {
+            DynamicImage::ImageRgba8(img.to_rgba8()).write_to(&mut buff, ImageFormat::Gif)?
+        }
+        Format::Webp => {
+            let encoder = webp::Encoder::from_image(&img).map_err(|e| anyhow!("{e}"))?;
+            let memory = if encoding.webp_lossless() {
+                encoder.encode_lossless()
+            } else {
+                encoder.encode(encoding.webp_quality())
+            };
+            return Ok(memory.as_bytes().to_vec());
+        }
+        Format::Avif => AvifEncoder::new_with_speed_quality(
+            &mut buff,
+            encoding.avif_speed,
+            encoding.avif_quality(),
+        )
+        .write_image(img.as_bytes(), img.width(), img.height(), img.color())?,
+        Format::Mp4 => return Err(anyhow!("mp4 can only be produced from videos")),
+        Format::Svg => return Err(anyhow!("svg can only be produced from svgs")),
+    };
+    Ok(buff.into_inner())
+}
+
+pub fn resize_png(data: &[u8], geometry: &Geometry, encoding: &Encoding) -> Result<Vec<u8>> {
     let start = Instant::now();
-    let decoder = png::Decoder::new(Cursor::new(data));
+    let mut decoder = png::Decoder::new(Cursor::new(data));
+    //palettes and low bit depths are expanded to 8 bit, and tRNS to an alpha channel
+    decoder.set_transformations(png::Transformations::EXPAND);
     let (info, mut reader) = decoder.read_info()?;
+    let indexed = reader.info().color_type == ColorType::Indexed;
     let mut src = vec![0; info.buffer_size()];
     reader.next_frame(&mut src)?;
-    let (w2, h2) = {
-        let x = calculate_dimensions(info.width, info.height, width);
-        (x.0 as usize, x.1 as usize)
+    //oriented, color managed and colored padding images go through the regular decoder
+    let orientation = geometry.orientation(data);
+    if !orientation.is_identity() || encoding.srgb || geometry.background.is_some() {
+        let img = to_srgb(image::load_from_memory(data)?, data, encoding);
+        let mut buff = Cursor::new(Vec::new());
+        apply_geometry(img, orientation, geometry)?.write_to(&mut buff, ImageFormat::Png)?;
+        info!(
+            "Resized to {}x{} px in {}",
+            geometry.width,
+            geometry.height,
+            Elapsed::from(&start)
+        );
+        return Ok(buff.into_inner());
+    }
+    let channels = info.color_type.samples();
+    let bytes = match info.bit_depth {
+        BitDepth::Sixteen => 2,
+        _ => 1,
+    };
+    let bpp = channels * bytes;
+    let luma = if geometry.needs_luma() {
+        Some(raw_luma(&src, info.width, info.height, channels, bytes))
+    } else {
+        None
     };
+    let layout = geometry.layout(info.width, info.height, luma.as_ref());
     //early exit
-    if width == info.width {
+    if layout.is_noop(info.width, info.height) && !encoding.is_custom() {
         return Ok(data.to_vec());
     };
-    let (w1, h1) = (info.width as usize, info.height as usize);
-    let mut dst = vec![0u8; w2 * h2 * info.color_type.samples()];
+    let (w2, h2) = (layout.scaled.0 as usize, layout.scaled.1 as usize);
+    let (cw, ch) = (layout.canvas.0 as usize, layout.canvas.1 as usize);
+    let src = if layout.region == Region::full(info.width, info.height) {
+        src
+    } else {
+        crop_buffer(&src, info.width as usize, layout.region, bpp)
+    };
+    let (w1, h1) = (layout.region.width as usize, layout.region.height as usize);
+    let dst = resize_raw(
+        &src,
+        (w1, h1),
+        (w2, h2),
+        info.color_type,
+        bytes,
+        geometry.filter(),
+    )?;
 
-    match info.color_type {
-        ColorType::Grayscale => resize::new(w1, h1, w2, h2, Pixel::Gray8, Triangle)?
-            .resize(src.as_gray(), dst.as_gray_mut())?,
-        ColorType::RGB => resize::new(w1, h1, w2, h2, Pixel::RGB8, Triangle)?
-            .resize(src.as_rgb(), dst.as_rgb_mut())?,
-        ColorType::Indexed => {
-            error!("Unimplemented conversion -> ColorType::Indexed");
-            unimplemented!()
-        }
-        ColorType::GrayscaleAlpha => {
-            error!("Unimplemented conversion -> ColorType::GrayscaleAlpha");
-            unimplemented!()
-        }
-        ColorType::RGBA => resize::new(w1, h1, w2, h2, Pixel::RGBA8, Triangle)?
-            .resize(src.as_rgba(), dst.as_rgba_mut())?,
+    let dst = if (w2, h2) == (cw, ch) {
+        dst
+    } else {
+        fit_buffer(&dst, (w2, h2), (cw, ch), bpp)
     };
 
     let mut buff = Cursor::new(Vec::new());
-    let mut encoder = png::Encoder::new(&mut buff, w2 as u32, h2 as u32);
-    encoder.set_color(info.color_type);
-    encoder.set_depth(info.bit_depth);
-    encoder
-        .write_header()
-        .unwrap()
-        .write_image_data(&dst)
-        .unwrap();
-    info!("Resized to {} px in {}", width, Elapsed::from(&start));
+    let mut encoder = png::Encoder::new(&mut buff, cw as u32, ch as u32);
+    let dst = if indexed && encoding.palette() {
+        let (palette, trns, indices) = quantize(&dst, channels);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.set_palette(palette);
+        if let Some(trns) = trns {
+            encoder.set_trns(trns);
+        }
+        indices
+    } else {
+        encoder.set_color(info.color_type);
+        encoder.set_depth(info.bit_depth);
+        dst
+    };
+    encoder.write_header()?.write_image_data(&dst)?;
+    info!("Resized to {}x{} px in {}", cw, ch, Elapsed::from(&start));
     Ok(buff.into_inner())
 }
+
+/// Resizes a packed buffer of 8 or 16 bit samples. 16 bit samples are big endian, as stored
+/// in PNG files.
+fn resize_raw(
+    src: &[u8],
+    (w1, h1): (usize, usize),
+    (w2, h2): (usize, usize),
+    color: ColorType,
+    bytes: usize,
+    filter: Filter,
+) -> Result<Vec<u8>> {
+    let resize_type = filter.resize_type();
+    //there is no gray + alpha pixel format, so those buffers are resized as rgba
+    let (src, channels) = match color {
+        ColorType::GrayscaleAlpha => (gray_alpha_to_rgba(src, bytes), 4),
+        c => (src.to_vec(), c.samples()),
+    };
+    let dst = if bytes == 2 {
+        let src: Vec<u16> = src
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect();
+        let mut dst = vec![0u16; w2 * h2 * channels];
+        match channels {
+            1 => resize::new(w1, h1, w2, h2, Pixel::Gray16, resize_type)?
+                .resize(src.as_gray(), dst.as_gray_mut())?,
+            3 => resize::new(w1, h1, w2, h2, Pixel::RGB16, resize_type)?
+                .resize(src.as_rgb(), dst.as_rgb_mut())?,
+            _ => resize::new(w1, h1, w2, h2, Pixel::RGBA16, resize_type)?
+                .resize(src.as_rgba(), dst.as_rgba_mut())?,
+        };
+        dst.iter().flat_map(|v| v.to_be_bytes()).collect()
+    } else {
+        let mut dst = vec![0u8; w2 * h2 * channels];
+        match channels {
+            1 => resize::new(w1, h1, w2, h2, Pixel::Gray8, resize_type)?
+                .resize(src.as_gray(), dst.as_gray_mut())?,
+            3 => resize::new(w1, h1, w2, h2, Pixel::RGB8, resize_type)?
+                .resize(src.as_rgb(), dst.as_rgb_mut())?,
+            _ => resize::new(w1, h1, w2, h2, Pixel::RGBA8, resize_type)?
+                .resize(src.as_rgba(), dst.as_rgba_mut())?,
+        };
+        dst
+    };
+    Ok(match color {
+        ColorType::GrayscaleAlpha => rgba_to_gray_alpha(&dst, bytes),
+        _ => dst,
+    })
+}
+
+fn gray_alpha_to_rgba(src: &[u8], bytes: usize) -> Vec<u8> {
+    src.chunks_exact(bytes * 2)
+        .flat_map(|p| {
+            let (gray, alpha) = p.split_at(bytes);
+            [gray, gray, gray, alpha].concat()
+        })
+        .collect()
+}
+
+fn rgba_to_gray_alpha(src: &[u8], bytes: usize) -> Vec<u8> {
+    src.chunks_exact(bytes * 4)
+        .flat_map(|p| [&p[..bytes], &p[bytes * 3..]].concat())
+        .collect()
+}
+
+/// Maps 8 bit RGB or RGBA pixels to a palette of at most 256 colors. Returns the palette, its
+/// tRNS alpha values when some color is transparent, and the indices. The exact colors are
+/// kept when they fit, which is the common case for pixel art resized with `nearest`.
+fn quantize(src: &[u8], channels: usize) -> (Vec<u8>, Option<Vec<u8>>, Vec<u8>) {
+    let rgba: Vec<u8> = match channels {
+        4 => src.to_vec(),
+        _ => src
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+    };
+    let mut colors: HashMap<&[u8], u8> = HashMap::new();
+    let exact = rgba.chunks_exact(4).all(|p| {
+        if colors.contains_key(p) {
+            true
+        } else if colors.len() < 256 {
+            colors.insert(p, colors.len() as u8);
+            true
+        } else {
+            false
+        }
+    });
+    let (palette, indices): (Vec<u8>, Vec<u8>) = if exact {
+        let mut palette = vec![0u8; colors.len() * 4];
+        for (color, i) in &colors {
+            let i = *i as usize * 4;
+            palette[i..i + 4].copy_from_slice(color);
+        }
+        let indices = rgba.chunks_exact(4).map(|p| colors[p]).collect();
+        (palette, indices)
+    } else {
+        let quant = color_quant::NeuQuant::new(10, 256, &rgba);
+        let indices = rgba
+            .chunks_exact(4)
+            .map(|p| quant.index_of(p) as u8)
+            .collect();
+        (quant.color_map_rgba(), indices)
+    };
+    let rgb = palette
+        .chunks_exact(4)
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect();
+    let trns = palette
+        .chunks_exact(4)
+        .any(|c| c[3] < 255)
+        .then(|| palette.chunks_exact(4).map(|c| c[3]).collect());
+    (rgb, trns, indices)
+}
@@ -0,0 +1,279 @@
+use crate::img::{self, Geometry};
+use crate::metadata;
+use anyhow::{anyhow, Result};
+use image::codecs::{bmp::BmpDecoder, jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder};
+use image::{ColorType, ImageDecoder, ImageFormat};
+use mime::Mime;
+use mp4::TrackType;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Track {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub codec: String,
+    /// Duration in seconds
+    pub duration: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+    /// Bits per second
+    pub bitrate: u32,
+    pub language: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MediaInfo {
+    /// Detected from the content, e.g. `png`, `jxl` or `mp4`
+    pub format: String,
+    pub content_type: String,
+    /// Size once oriented, which is how the image is served
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+    /// Length of animations and videos, in seconds
+    pub duration: Option<f32>,
+    /// File size in bytes
+    pub size: usize,
+    /// Decoded pixel layout, e.g. `rgb8` or `rgba8`. Animations always decode to `rgba8`.
+    pub color_type: Option<String>,
+    pub alpha: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracks: Vec<Track>,
+}
+
+impl MediaInfo {
+    pub fn new(data: &[u8], mime: &Mime) -> Result<Self> {
+        let mut info = match mime.essence_str() {
+            "video/mp4" => Self::mp4(data)?,
+            "image/gif" => Self::gif(data)?,
+            "image/webp" if img::is_webp_animated(data) => Self::animated_webp(data)?,
+            _ if img::is_apng(data) => Self::apng(data, mime)?,
+            _ => Self::still(data, mime)?,
+        };
+        info.content_type = mime.essence_str().to_string();
+        info.size = data.len();
+        Ok(info)
+    }
+
+    fn still(data: &[u8], mime: &Mime) -> Result<Self> {
+        //common formats are described by their headers, so large images are never decoded
+        let (width, height, color) = match header(data, mime)? {
+            Some((w, h, color)) => {
+                let (w, h) = Geometry::default().orientation(data).size(w, h);
+                (w, h, color)
+            }
+            None => {
+                let img = img::decode_upright(data, mime)?;
+                (img.width(), img.height(), img.color())
+            }
+        };
+        let format = if mime.essence_str() == mime::IMAGE_SVG.essence_str() {
+            "svg".to_string()
+        } else if img::is_jxl(data) {
+            "jxl".to_string()
+        } else {
+            match image::guess_format(data) {
+                Ok(f) => format!("{:?}", f).to_lowercase(),
+                Err(_) => mime.subtype().to_string(),
+            }
+        };
+        Ok(Self {
+            format,
+            width,
+            height,
+            frames: 1,
+            color_type: Some(format!("{:?}", color).to_lowercase()),
+            alpha: color.has_alpha(),
+            ..Default::default()
+        })
+    }
+
+    fn apng(data: &[u8], mime: &Mime) -> Result<Self> {
+        //the default image is the first frame
+        let mut info = Self::still(data, mime)?;
+        let chunks = metadata::png_chunks(data)?;
+        info.frames = chunks
+            .iter()
+            .find(|(kind, _)| kind == b"acTL")
+            .and_then(|(_, body)| body.get(0..4))
+            .map_or(1, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+        //a zero denominator means hundredths of a second
+        let duration = chunks
+            .iter()
+            .filter(|(kind, _)| kind == b"fcTL")
+            .filter_map(|(_, body)| body.get(20..24))
+            .map(|b| {
+                let num = u16::from_be_bytes([b[0], b[1]]) as f32;
+                let den = u16::from_be_bytes([b[2], b[3]]);
+                num / if den == 0 { 100.0 } else { den as f32 }
+            })
+            .sum();
+        info.duration = Some(duration);
+        info.color_type = Some("rgba8".to_string());
+        Ok(info)
+    }
+
+    fn gif(data: &[u8]) -> Result<Self> {
+        let mut decoder = gif::DecodeOptions::new().read_info(data)?;
+        let (width, height) = (decoder.width() as u32, decoder.height() as u32);
+        let (mut frames, mut delay, mut alpha) = (0, 0, false);
+        //only the frame headers are needed, pixels are never composited
+        while let Some(frame) = decoder.next_frame_info()? {
+            frames += 1;
+            delay += frame.delay as u32;
+            alpha |= frame.transparent.is_some();
+        }
+        Ok(Self {
+            format: "gif".to_string(),
+            width,
+            height,
+            frames,
+            //delays are in hundredths of a second
+            duration: (frames > 1).then_some(delay as f32 / 100.0),
+            color_type: Some("rgba8".to_string()),
+            alpha,
+            ..Default::default()
+        })
+    }
+
+    fn animated_webp(data: &[u8]) -> Result<Self> {
+        let chunks = metadata::webp_chunks(data)?;
+        let vp8x = chunks
+            .iter()
+            .find(|(kind, _)| kind == b"VP8X")
+            .and_then(|(_, body)| body.get(0..10))
+            .ok_or_else(|| anyhow!("animated webp without a VP8X chunk"))?;
+        //canvas size is stored minus one
+        let (w, h) = (u24(&vp8x[4..7]) + 1, u24(&vp8x[7..10]) + 1);
+        let (width, height) = Geometry::default().orientation(data).size(w, h);
+        let delays: Vec<u32> = chunks
+            .iter()
+            .filter(|(kind, _)| kind == b"ANMF")
+            .filter_map(|(_, body)| body.get(12..15))
+            .map(u24)
+            .collect();
+        Ok(Self {
+            format: "webp".to_string(),
+            width,
+            height,
+            frames: delays.len() as u32,
+            //delays are in milliseconds
+            duration: Some(delays.iter().sum::<u32>() as f32 / 1000.0),
+            color_type: Some("rgba8".to_string()),
+            alpha: vp8x[0] & 0x10 != 0,
+            ..Default::default()
+        })
+    }
+
+    fn mp4(data: &[u8]) -> Result<Self> {
+        let mp4 = mp4::Mp4Reader::read_header(Cursor::new(data), data.len() as u64)?;
+        let mut tracks: Vec<&mp4::Mp4Track> = mp4.tracks().values().collect();
+        tracks.sort_by_key(|t| t.track_id());
+        //audio only files have no size or frames
+        let video = tracks
+            .iter()
+            .find(|t| matches!(t.track_type(), Ok(TrackType::Video)));
+        let tracks = tracks
+            .iter()
+            .map(|t| {
+                let size = matches!(t.track_type(), Ok(TrackType::Video))
+                    .then(|| img::mp4_display_size(t));
+                Track {
+                    kind: t
+                        .track_type()
+                        .map_or("unknown".to_string(), |k| k.to_string()),
+                    codec: t
+                        .media_type()
+                        .map_or("unknown".to_string(), |m| m.to_string()),
+                    duration: t.duration().as_secs_f32(),
+                    width: size.map(|(w, _)| w),
+                    height: size.map(|(_, h)| h),
+                    frame_rate: size.map(|_| t.frame_rate()),
+                    bitrate: t.bitrate(),
+                    language: t.language().to_string(),
+                }
+            })
+            .collect();
+        let (width, height) = video.map_or((0, 0), |v| img::mp4_display_size(v));
+        Ok(Self {
+            format: "mp4".to_string(),
+            width,
+            height,
+            frames: video.map_or(0, |v| v.sample_count()),
+            duration: Some(video.map_or(mp4.duration(), |v| v.duration()).as_secs_f32()),
+            tracks,
+            ..Default::default()
+        })
+    }
+}
+
+/// Size and color type read from the headers of PNG, JPEG, TIFF, BMP and WebP stills. Other
+/// formats are decoded.
+fn header(data: &[u8], mime: &Mime) -> Result<Option<(u32, u32, ColorType)>> {
+    if mime.essence_str() == mime::IMAGE_SVG.essence_str() || img::is_jxl(data) {
+        return Ok(None);
+    }
+    let format = match image::guess_format(data) {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+    let cursor = Cursor::new(data);
+    Ok(Some(match format {
+        ImageFormat::Png => describe(PngDecoder::new(cursor)?),
+        ImageFormat::Jpeg => describe(JpegDecoder::new(cursor)?),
+        ImageFormat::Tiff => describe(TiffDecoder::new(cursor)?),
+        ImageFormat::Bmp => describe(BmpDecoder::new(cursor)?),
+        //the webp decoder of the image crate reads the whole bitstream up front
+        ImageFormat::WebP => return Ok(webp_header(data)),
+        _ => return Ok(None),
+    }))
+}
+
+fn describe<'a>(decoder: impl ImageDecoder<'a>) -> (u32, u32, ColorType) {
+    let (w, h) = decoder.dimensions();
+    (w, h, decoder.color_type())
+}
+
+/// Canvas size and color type of a still WebP. The image crate decodes it as `rgb8` unless
+/// it's lossless or has an alpha channel.
+fn webp_header(data: &[u8]) -> Option<(u32, u32, ColorType)> {
+    let chunks = metadata::webp_chunks(data).ok()?;
+    let (kind, body) = chunks.first()?;
+    match kind {
+        //canvas size is stored minus one
+        b"VP8X" if body.len() >= 10 => {
+            let color = if body[0] & 0x10 != 0 {
+                ColorType::Rgba8
+            } else {
+                ColorType::Rgb8
+            };
+            Some((u24(&body[4..7]) + 1, u24(&body[7..10]) + 1, color))
+        }
+        //14 bit dimensions after the frame tag and the start code
+        b"VP8 " if body.len() >= 10 => Some((
+            u16::from_le_bytes([body[6], body[7]]) as u32 & 0x3FFF,
+            u16::from_le_bytes([body[8], body[9]]) as u32 & 0x3FFF,
+            ColorType::Rgb8,
+        )),
+        //14 bit dimensions stored minus one after the signature
+        b"VP8L" if body.len() >= 5 => {
+            let bits = u32::from_le_bytes([body[1], body[2], body[3], body[4]]);
+            Some((
+                (bits & 0x3FFF) + 1,
+                ((bits >> 14) & 0x3FFF) + 1,
+                ColorType::Rgba8,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Little endian 24 bit integer, as used by WebP headers
+fn u24(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], 0])
+}
@@ -16,10 +16,15 @@ use std::{
     sync::Arc,
     time::{Duration, Instant},
 };
+mod colors;
 mod config;
 mod img;
+mod info;
+mod metadata;
 mod object;
+mod placeholder;
 mod routes;
+mod svg;
 mod tw;
 mod utils;
 
@@ -86,6 +91,9 @@ async fn main() -> std::io::Result<()> {
             .app_data(Data::new(cf_client.clone()))
             .service(public::twitter)
             .service(public::get)
+            .service(public::placeholder)
+            .service(public::colors)
+            .service(public::info)
             .service(public::fetch_object)
             .service(public::forward)
             .service(
@@ -0,0 +1,463 @@
+use anyhow::{anyhow, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+pub(crate) const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+//a JPEG segment holds up to 65533 bytes, minus the ICC_PROFILE header and chunk numbers
+const ICC_CHUNK_SIZE: usize = 65519;
+
+/// Metadata kept in processed outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strip {
+    /// Drop every metadata block, including the ICC profile
+    All,
+    /// Drop EXIF (which holds the GPS data) and XMP, keep the ICC profile
+    Exif,
+    /// Keep everything
+    None,
+}
+
+impl Default for Strip {
+    fn default() -> Self {
+        Self::Exif
+    }
+}
+
+impl fmt::Display for Strip {
+    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let strip = match self {
+            Self::All => "all",
+            Self::Exif => "exif",
+            Self::None => "none",
+        };
+        write!(out, "{}", strip)
+    }
+}
+
+/// Metadata blocks of a JPEG, PNG or WebP container
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub icc: Option<Vec<u8>>,
+    /// TIFF structure, without the `Exif` header
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Reads the metadata of the source. Unknown or broken containers have none.
+    pub fn read(data: &[u8]) -> Metadata {
+        let meta = if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            read_jpeg(data)
+        } else if data.starts_with(PNG_SIGNATURE) {
+            read_png(data)
+        } else if is_webp(data) {
+            read_webp(data)
+        } else {
+            return Metadata::default();
+        };
+        meta.unwrap_or_else(|e| {
+            log::warn!("Unable to read metadata: {e}");
+            Metadata::default()
+        })
+    }
+
+    pub fn strip(self, strip: Strip) -> Metadata {
+        match strip {
+            Strip::All => Metadata::default(),
+            Strip::Exif => Metadata {
+                icc: self.icc,
+                ..Default::default()
+            },
+            Strip::None => self,
+        }
+    }
+
+    /// Marks the image as upright, for outputs that were already rotated
+    pub fn reset_orientation(&mut self) {
+        if let Some(exif) = &mut self.exif {
+            reset_orientation(exif);
+        }
+    }
+
+    /// Replaces the metadata of an encoded JPEG, PNG or WebP with this one.
+    /// Other containers are returned as is.
+    pub fn write(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            self.write_jpeg(data)
+        } else if data.starts_with(PNG_SIGNATURE) {
+            self.write_png(data)
+        } else if is_webp(data) {
+            self.write_webp(data)
+        } else {
+            Ok(data.to_vec())
+        }
+    }
+
+    fn write_jpeg(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (segments, sos) = jpeg_segments(data)?;
+        let mut out = vec![0xFF, 0xD8];
+        //JFIF has to stay the first segment
+        for (marker, body) in segments.iter().filter(|(m, _)| *m == 0xE0) {
+            push_jpeg_segment(&mut out, *marker, &[*body])?;
+        }
+        if let Some(exif) = &self.exif {
+            push_jpeg_segment(&mut out, 0xE1, &[EXIF_HEADER, exif.as_slice()])?;
+        }
+        if let Some(xmp) = &self.xmp {
+            push_jpeg_segment(&mut out, 0xE1, &[XMP_HEADER, xmp.as_slice()])?;
+        }
+        if let Some(icc) = &self.icc {
+            let count = icc.chunks(ICC_CHUNK_SIZE).count();
+            if count > u8::MAX as usize {
+                return Err(anyhow!("icc profile too large"));
+            }
+            for (i, chunk) in icc.chunks(ICC_CHUNK_SIZE).enumerate() {
+                let seq = [i as u8 + 1, count as u8];
+                push_jpeg_segment(&mut out, 0xE2, &[ICC_HEADER, &seq, chunk])?;
+            }
+        }
+        for (marker, body) in segments
+            .iter()
+            .filter(|(m, b)| *m != 0xE0 && !is_jpeg_metadata(*m, b))
+        {
+            push_jpeg_segment(&mut out, *marker, &[*body])?;
+        }
+        out.extend_from_slice(&data[sos..]);
+        Ok(out)
+    }
+
+    fn write_png(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = PNG_SIGNATURE.to_vec();
+        for (kind, body) in png_chunks(data)? {
+            let skip = match &kind {
+                b"iCCP" | b"eXIf" => true,
+                //an ICC profile overrides the sRGB chunk
+                b"sRGB" => self.icc.is_some(),
+                b"iTXt" => body.starts_with(XMP_KEYWORD),
+                _ => false,
+            };
+            if skip {
+                continue;
+            }
+            push_png_chunk(&mut out, &kind, &[body]);
+            //everything we write has to come before the palette and image data
+            if &kind == b"IHDR" {
+                if let Some(icc) = &self.icc {
+                    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                    encoder.write_all(icc)?;
+                    push_png_chunk(
+                        &mut out,
+                        b"iCCP",
+                        &[b"icc\0\0".as_slice(), &encoder.finish()?],
+                    );
+                }
+                if let Some(exif) = &self.exif {
+                    push_png_chunk(&mut out, b"eXIf", &[exif.as_slice()]);
+                }
+                if let Some(xmp) = &self.xmp {
+                    //uncompressed, without language tag or translated keyword
+                    push_png_chunk(
+                        &mut out,
+                        b"iTXt",
+                        &[XMP_KEYWORD, b"\0\0\0\0\0", xmp.as_slice()],
+                    );
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn write_webp(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let chunks = webp_chunks(data)?;
+        let image: Vec<_> = chunks
+            .iter()
+            .filter(|(kind, _)| !matches!(kind, b"VP8X" | b"ICCP" | b"EXIF" | b"XMP "))
+            .collect();
+        //flags and canvas size from the extended header, or from the simple bitstream
+        let (mut flags, canvas) = match chunks.first().map(|(kind, body)| (kind, *body)) {
+            Some((b"VP8X", body)) if body.len() >= 10 => (body[0], body[4..10].to_vec()),
+            Some((b"VP8L", body)) if body.len() >= 5 && body[0] == 0x2F => {
+                let bits = u32::from_le_bytes([body[1], body[2], body[3], body[4]]);
+                let (w, h) = (bits & 0x3FFF, (bits >> 14) & 0x3FFF);
+                let alpha = if (bits >> 28) & 1 == 1 { 0x10 } else { 0 };
+                (
+                    alpha,
+                    [&w.to_le_bytes()[..3], &h.to_le_bytes()[..3]].concat(),
+                )
+            }
+            Some((b"VP8 ", body)) if body.len() >= 10 && body[3..6] == [0x9D, 0x01, 0x2A] => {
+                let w = (u16::from_le_bytes([body[6], body[7]]) & 0x3FFF) as u32 - 1;
+                let h = (u16::from_le_bytes([body[8], body[9]]) & 0x3FFF) as u32 - 1;
+                (0, [&w.to_le_bytes()[..3], &h.to_le_bytes()[..3]].concat())
+            }
+            _ => return Err(anyhow!("unknown webp bitstream")),
+        };
+        flags &= !(0x20 | 0x08 | 0x04);
+        let extended = matches!(chunks.first(), Some((kind, _)) if kind == b"VP8X");
+        let mut body = Vec::new();
+        if extended || self.icc.is_some() || self.exif.is_some() || self.xmp.is_some() {
+            let icc = self.icc.as_ref().map_or(0, |_| 0x20);
+            let exif = self.exif.as_ref().map_or(0, |_| 0x08);
+            let xmp = self.xmp.as_ref().map_or(0, |_| 0x04);
+            let header = [&[flags | icc | exif | xmp, 0, 0, 0][..], &canvas].concat();
+            push_webp_chunk(&mut body, b"VP8X", &header);
+        }
+        if let Some(icc) = &self.icc {
+            push_webp_chunk(&mut body, b"ICCP", icc);
+        }
+        for (kind, chunk) in image {
+            push_webp_chunk(&mut body, kind, chunk);
+        }
+        if let Some(exif) = &self.exif {
+            push_webp_chunk(&mut body, b"EXIF", exif);
+        }
+        if let Some(xmp) = &self.xmp {
+            push_webp_chunk(&mut body, b"XMP ", xmp);
+        }
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+}
+
+fn is_webp(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP"
+}
+
+fn is_jpeg_metadata(marker: u8, body: &[u8]) -> bool {
+    match marker {
+        0xE1 => body.starts_with(EXIF_HEADER) || body.starts_with(XMP_HEADER),
+        0xE2 => body.starts_with(ICC_HEADER),
+        _ => false,
+    }
+}
+
+/// Splits the header of a JPEG into segments, up to the start of scan.
+/// Returns the segments and the offset of the start of scan.
+fn jpeg_segments(data: &[u8]) -> Result<(Vec<(u8, &[u8])>, usize)> {
+    let mut segments = Vec::new();
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            return Err(anyhow!("invalid jpeg marker at {i}"));
+        }
+        let marker = data[i + 1];
+        //fill byte
+        if marker == 0xFF {
+            i += 1;
+            continue;
+        }
+        if marker == 0xDA {
+            return Ok((segments, i));
+        }
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        let end = i + 2 + len;
+        if len < 2 || end > data.len() {
+            return Err(anyhow!("truncated jpeg segment at {i}"));
+        }
+        segments.push((marker, &data[i + 4..end]));
+        i = end;
+    }
+    Err(anyhow!("jpeg without image data"))
+}
+
+fn push_jpeg_segment(out: &mut Vec<u8>, marker: u8, parts: &[&[u8]]) -> Result<()> {
+    let len = parts.iter().map(|p| p.len()).sum::<usize>() + 2;
+    if len > u16::MAX as usize {
+        return Err(anyhow!("jpeg segment too large"));
+    }
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+    parts.iter().for_each(|p| out.extend_from_slice(p));
+    Ok(())
+}
+
+fn read_jpeg(data: &[u8]) -> Result<Metadata> {
+    let (segments, _) = jpeg_segments(data)?;
+    let mut meta = Metadata::default();
+    let mut icc = Vec::new();
+    for (marker, body) in segments {
+        match marker {
+            0xE1 if body.starts_with(EXIF_HEADER) => {
+                meta.exif = Some(body[EXIF_HEADER.len()..].to_vec())
+            }
+            0xE1 if body.starts_with(XMP_HEADER) => {
+                meta.xmp = Some(body[XMP_HEADER.len()..].to_vec())
+            }
+            //the profile can be split across several segments, numbered from 1
+            0xE2 if body.starts_with(ICC_HEADER) && body.len() > 14 => {
+                icc.push((body[12], &body[14..]))
+            }
+            _ => {}
+        }
+    }
+    if !icc.is_empty() {
+        icc.sort_by_key(|(seq, _)| *seq);
+        meta.icc = Some(icc.into_iter().flat_map(|(_, c)| c.to_vec()).collect());
+    }
+    Ok(meta)
+}
+
+pub(crate) fn png_chunks(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut chunks = Vec::new();
+    let mut i = PNG_SIGNATURE.len();
+    while i + 12 <= data.len() {
+        let len = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
+        let kind = [data[i + 4], data[i + 5], data[i + 6], data[i + 7]];
+        let end = i + 12 + len;
+        if end > data.len() {
+            return Err(anyhow!("truncated png chunk at {i}"));
+        }
+        chunks.push((kind, &data[i + 8..end - 4]));
+        i = end;
+        if &kind == b"IEND" {
+            return Ok(chunks);
+        }
+    }
+    Err(anyhow!("png without IEND chunk"))
+}
+
+pub(crate) fn push_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], parts: &[&[u8]]) {
+    let len = parts.iter().map(|p| p.len()).sum::<usize>() as u32;
+    out.extend_from_slice(&len.to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    parts.iter().for_each(|p| out.extend_from_slice(p));
+    let crc = crc32fast::hash(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+fn read_png(data: &[u8]) -> Result<Metadata> {
+    let mut meta = Metadata::default();
+    for (kind, body) in png_chunks(data)? {
+        match &kind {
+            //profile name, compression method and the zlib stream
+            b"iCCP" => {
+                let name = body.iter().position(|b| *b == 0).unwrap_or(body.len());
+                meta.icc = Some(inflate(body.get(name + 2..).unwrap_or_default())?);
+            }
+            b"eXIf" => meta.exif = Some(body.to_vec()),
+            //keyword, compression flag and method, language tag and translated keyword
+            b"iTXt" if body.starts_with(XMP_KEYWORD) => {
+                let compressed = body.get(XMP_KEYWORD.len() + 1) == Some(&1);
+                let text = body
+                    .iter()
+                    .enumerate()
+                    .skip(XMP_KEYWORD.len() + 3)
+                    .filter(|(_, b)| **b == 0)
+                    .nth(1)
+                    .map_or(&[][..], |(i, _)| &body[i + 1..]);
+                meta.xmp = Some(if compressed {
+                    inflate(text)?
+                } else {
+                    text.to_vec()
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(meta)
+}
+
+pub(crate) fn webp_chunks(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut chunks = Vec::new();
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let kind = [data[i], data[i + 1], data[i + 2], data[i + 3]];
+        let len = u32::from_le_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]);
+        let end = i + 8 + len as usize;
+        if end > data.len() {
+            return Err(anyhow!("truncated webp chunk at {i}"));
+        }
+        chunks.push((kind, &data[i + 8..end]));
+        //chunks are padded to an even size
+        i = end + (len as usize & 1);
+    }
+    Ok(chunks)
+}
+
+fn push_webp_chunk(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn read_webp(data: &[u8]) -> Result<Metadata> {
+    let mut meta = Metadata::default();
+    for (kind, body) in webp_chunks(data)? {
+        match &kind {
+            b"ICCP" => meta.icc = Some(body.to_vec()),
+            //some writers keep the JPEG header
+            b"EXIF" => meta.exif = Some(body.strip_prefix(EXIF_HEADER).unwrap_or(body).to_vec()),
+            b"XMP " => meta.xmp = Some(body.to_vec()),
+            _ => {}
+        }
+    }
+    Ok(meta)
+}
+
+/// Sets the orientation tag of the first IFD to 1 (upright)
+fn reset_orientation(tiff: &mut [u8]) {
+    let le = match tiff.get(0..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return,
+    };
+    let read16 = |b: &[u8]| {
+        if le {
+            u16::from_le_bytes([b[0], b[1]])
+        } else {
+            u16::from_be_bytes([b[0], b[1]])
+        }
+    };
+    let read32 = |b: &[u8]| {
+        if le {
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        } else {
+            u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+        }
+    };
+    let ifd = match tiff.get(4..8) {
+        Some(b) => read32(b) as usize,
+        None => return,
+    };
+    let count = match tiff.get(ifd..ifd + 2) {
+        Some(b) => read16(b) as usize,
+        None => return,
+    };
+    for n in 0..count {
+        let entry = ifd + 2 + n * 12;
+        let tag = match tiff.get(entry..entry + 12) {
+            Some(b) => read16(b),
+            None => return,
+        };
+        //SHORT values are stored at the start of the value field
+        if tag == 0x0112 {
+            let upright = if le {
+                1u16.to_le_bytes()
+            } else {
+                1u16.to_be_bytes()
+            };
+            tiff[entry + 8..entry + 10].copy_from_slice(&upright);
+            return;
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use crate::{
-    config::{AppConfig, CacheConfig, Origin},
-    img,
+    colors,
+    config::{AppConfig, CacheConfig, Origin, VideoConfig},
+    img::{self, Encoding, Format, Frame, Geometry},
+    metadata::Metadata,
+    placeholder,
     routes::ErrorResponse,
+    svg,
     utils::{self, Elapsed},
     CONTENT_TYPE,
     {web::Data, HeaderMap, HttpResponse, StatusCode},
@@ -29,7 +33,11 @@ pub struct Object {
     #[derivative(Default(value = "mime::TEXT_PLAIN"))]
     pub content_type: Mime,
     pub origin: Origin,
-    pub scale: u32,
+    pub geometry: Geometry,
+    pub format: Option<Format>,
+    pub frame: Option<Frame>,
+    pub encoding: Encoding,
+    pub video: VideoConfig,
     pub paths: Paths,
     pub retries: u32,
     pub status: Option<StatusCode>,
@@ -40,6 +48,9 @@ pub struct Object {
 pub struct Paths {
     pub base: String,
     pub modified: String,
+    /// Variants the Accept header could pick when no format was requested
+    pub negotiated: Vec<String>,
+    pub tmp: String,
 }
 #[derive(Serialize, Deserialize)]
 struct RetryCount {
@@ -62,6 +73,7 @@ impl Object {
             name: "misc".to_string(),
             endpoint: url,
             cache: CacheConfig::default(),
+            ..Default::default()
         };
         obj.name = obj.get_hash();
         obj
@@ -69,19 +81,155 @@ impl Object {
 
     pub fn create_dir(&self, path: &str) -> Result<()> {
         fs::create_dir_all(format!("{}/base/{}", path, self.origin.name))?;
-        if self.scale != 0 {
-            fs::create_dir_all(format!("{}/mod/{}/{}", path, self.origin.name, self.scale))?;
+        if self.is_modified() {
+            fs::create_dir_all(self.mod_dir(path))?;
         }
         Ok(())
     }
 
+    /// Whether the request asks for a processed version of the object
+    pub fn is_modified(&self) -> bool {
+        !self.geometry.is_empty()
+            || self.format.is_some()
+            || self.frame.is_some()
+            || self.encoding.is_custom()
+    }
+
+    /// Picks the best format the client accepts when no output format was requested.
+    /// Still images, GIFs and APNGs are negotiated, and SVGs only when they are rasterized
+    /// anyway. Returns true when the response depends on the Accept header.
+    pub fn negotiate(&mut self, accept: Option<&str>) -> bool {
+        let candidates: &[Format] = match self.content_type.essence_str() {
+            //only WebP keeps the animation
+            _ if img::is_apng(&self.data) => &[Format::Webp],
+            //vectors are smaller and sharper than any raster, unless a geometry was requested
+            "image/svg+xml" if !self.geometry.is_empty() => &img::NEGOTIABLE,
+            _ => Format::negotiable(&self.content_type),
+        };
+        if self.format.is_some() || candidates.is_empty() {
+            return false;
+        }
+        self.format = accept
+            .and_then(|a| Format::from_accept(a, candidates))
+            .or_else(|| Format::fallback(&self.content_type));
+        true
+    }
+
+    /// Rejects the params that can't be applied to the object, once its type is known
+    pub fn check_params(&self) -> Result<(), HttpResponse> {
+        let input = self.content_type.essence_str();
+        match self.format {
+            Some(f @ Format::Svg) if input != "image/svg+xml" => {
+                Err(invalid_value("format", f.to_string()))
+            }
+            Some(f @ Format::Mp4) if input != "video/mp4" => {
+                Err(invalid_value("format", f.to_string()))
+            }
+            //posters are only taken from videos
+            _ => match self.frame {
+                Some(frame) if input != "video/mp4" => {
+                    Err(invalid_value("frame", frame.to_string()))
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Encoder settings with the origin default quality applied when none was requested
+    fn output_encoding(&self) -> Encoding {
+        Encoding {
+            quality: self.encoding.quality.or(self.origin.default_quality),
+            strip: self.encoding.strip.or(self.origin.default_strip),
+            ..self.encoding
+        }
+    }
+
+    /// Geometry with the origin default filter applied when none was requested
+    fn output_geometry(&self) -> Geometry {
+        Geometry {
+            filter: self.geometry.filter.or(self.origin.default_filter),
+            ..self.geometry
+        }
+    }
+
+    /// Requested output format, when it differs from the format of the object itself
+    fn conversion(&self) -> Option<Format> {
+        let input = Format::from_mime(&self.content_type)?;
+        self.format.filter(|f| *f != input)
+    }
+
+    /// Cache key for everything that changes the output besides the width.
+    /// Empty when only the width was requested, to keep the original layout.
+    fn variant(&self) -> String {
+        let mut variant = Vec::new();
+        if self.geometry.height != 0 {
+            variant.push(format!("h{}", self.geometry.height));
+        }
+        if self.geometry.fit != img::Fit::default() {
+            variant.push(self.geometry.fit.to_string());
+        }
+        if let Some(c) = self.geometry.crop {
+            variant.push(format!("c{}_{}_{}_{}", c.x, c.y, c.width, c.height));
+        }
+        if let Some(img::Aspect(w, h)) = self.geometry.aspect {
+            variant.push(format!("a{}_{}", w, h));
+        }
+        if self.geometry.gravity != img::Gravity::default() {
+            variant.push(self.geometry.gravity.to_string());
+        }
+        if !self.geometry.auto_orient {
+            variant.push("noorient".to_string());
+        }
+        if let Some(rotate) = self.geometry.rotate {
+            variant.push(format!("r{}", rotate));
+        }
+        if let Some(flip) = self.geometry.flip {
+            variant.push(format!("f{}", flip));
+        }
+        if let Some(filter) = self.output_geometry().filter {
+            variant.push(filter.to_string());
+        }
+        if let Some(background) = self.geometry.background {
+            variant.push(format!("bg{}", background));
+        }
+        if let Some(frame) = self.frame {
+            variant.push(frame.to_string().replace('=', ""));
+        }
+        if let Some(format) = self.format {
+            variant.push(format.to_string());
+        }
+        if let Some(quality) = self.output_encoding().quality {
+            variant.push(format!("q{}", quality));
+        }
+        if self.encoding.lossless {
+            variant.push("lossless".to_string());
+        }
+        if let Some(strip) = self.output_encoding().strip {
+            variant.push(format!("s{}", strip));
+        }
+        match self.encoding.palette {
+            Some(true) => variant.push("palette".to_string()),
+            Some(false) => variant.push("nopalette".to_string()),
+            None => {}
+        }
+        variant.join("-")
+    }
+
+    fn mod_dir(&self, path: &str) -> String {
+        let dir = format!("{}/mod/{}/{}", path, self.origin.name, self.geometry.width);
+        match self.variant().as_str() {
+            "" => dir,
+            v => format!("{}/{}", dir, v),
+        }
+    }
+
     pub fn try_open(&mut self) -> Result<&Self, Box<dyn std::error::Error>> {
         let valid_base = std::path::Path::new(&self.paths.base).exists();
         let valid_mod = std::path::Path::new(&self.paths.modified).exists();
         self.data = if valid_base && !valid_mod {
             self.content_type = utils::guess_content_type(&self.paths.base)?;
             utils::read_from_file(&self.paths.base)?
-        } else if self.scale != 0 && valid_mod {
+        } else if self.is_modified() && valid_mod {
             self.content_type = utils::guess_content_type(&self.paths.modified)?;
             utils::read_from_file(&self.paths.modified)?
         } else {
@@ -102,16 +250,23 @@ impl Object {
     }
 
     pub fn set_paths(&mut self, path: &str) -> &mut Self {
+        let mut negotiated = Vec::new();
+        if self.format.is_none() {
+            for format in [Format::Avif, Format::Webp, Format::Png] {
+                self.format = Some(format);
+                negotiated.push(format!("{}/{}", self.mod_dir(path), self.name));
+            }
+            self.format = None;
+        }
         self.paths = Paths {
-            modified: if self.scale != 0 {
-                format!(
-                    "{}/mod/{}/{}/{}",
-                    path, self.origin.name, self.scale, self.name
-                )
+            modified: if self.is_modified() {
+                format!("{}/{}", self.mod_dir(path), self.name)
             } else {
                 String::new()
             },
             base: format!("{}/base/{}/{}", path, self.origin.name, self.name),
+            negotiated,
+            tmp: format!("{}/tmp", path),
         };
         self
     }
@@ -122,8 +277,28 @@ impl Object {
         self
     }
 
-    pub fn scale(&mut self, scale: u32) -> &mut Self {
-        self.scale = scale;
+    pub fn geometry(&mut self, geometry: Geometry) -> &mut Self {
+        self.geometry = geometry;
+        self
+    }
+
+    pub fn format(&mut self, format: Option<Format>) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    pub fn frame(&mut self, frame: Option<Frame>) -> &mut Self {
+        self.frame = frame;
+        self
+    }
+
+    pub fn encoding(&mut self, encoding: Encoding) -> &mut Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn video(&mut self, video: VideoConfig) -> &mut Self {
+        self.video = video;
         self
     }
 
@@ -133,25 +308,6 @@ impl Object {
             .to_string()
     }
 
-    pub fn get_cf_path(&self) -> String {
-        match self.origin.name.as_ref() {
-            "misc" => format!("/?width={}&url={}", self.scale, self.origin.endpoint),
-            _ => {
-                let obj = self.name.split("-_-").collect::<Vec<&str>>();
-                let path = obj
-                    .get(1)
-                    .map(|p| format!("&path={}", p))
-                    .unwrap_or_default();
-                format!(
-                    "/{}/{}?width={}{}",
-                    self.origin.name,
-                    obj.first().expect("error while reading filename"),
-                    self.scale,
-                    path
-                )
-            }
-        }
-    }
     pub fn skip(&self) -> Result<HttpResponse> {
         let msg = format!(
             "Max retries reached for url: [{}] {}  | ",
@@ -166,7 +322,7 @@ impl Object {
     }
 
     pub fn save(&self, payload: Vec<u8>) -> Result<()> {
-        if payload != self.data && self.scale != 0 {
+        if payload != self.data && self.is_modified() {
             utils::write_to_file(payload, &self.paths.modified)?;
         }
         Ok(())
@@ -185,6 +341,29 @@ impl Object {
             Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
             r => r.expect("failed to remove file"),
         }
+        for path in &self.paths.negotiated {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                r => r.expect("failed to remove file"),
+            }
+        }
+        //placeholders, palettes and info computed from the base object
+        for kind in placeholder::Kind::ALL {
+            match std::fs::remove_file(format!("{}.{}.json", self.paths.base, kind)) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                r => r.expect("failed to remove file"),
+            }
+        }
+        match std::fs::remove_file(format!("{}.info.json", self.paths.base)) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            r => r.expect("failed to remove file"),
+        }
+        for count in 1..=colors::MAX_COLORS {
+            match std::fs::remove_file(format!("{}.colors-{}.json", self.paths.base, count)) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                r => r.expect("failed to remove file"),
+            }
+        }
         Ok(())
     }
     pub async fn reset_retries(
@@ -330,50 +509,129 @@ impl Object {
         let mut file = File::create(&self.paths.base)?;
         file.write_all(&self.data)?;
         debug!("it took {} to save object to disk", Elapsed::from(&start));
+        if self.content_type == mime::APPLICATION_OCTET_STREAM {
+            match utils::guess_content_type(&self.paths.base) {
+                Ok(guessed) => self.content_type = guessed,
+                Err(e) => warn!("Unable to guess format of obj from: {} - {}", self.url, e),
+            }
+            debug!("mime guessed from content: {}", self.content_type);
+        }
         Ok(self)
     }
 
-    pub fn process(&self, engine: u32) -> Result<(Mime, Vec<u8>)> {
-        let scale = self.scale;
+    pub fn process(&self) -> Result<(Mime, Vec<u8>)> {
+        //AVIF inputs can only be decoded when built with dav1d
+        if self.content_type.essence_str() == "image/avif" && !cfg!(feature = "avif-decoder") {
+            warn!(
+                "Serving avif obj from: {} as is, build with the avif-decoder feature to process it",
+                self.url
+            );
+            return Ok((self.content_type.clone(), self.data.clone()));
+        }
+        let geometry = &self.output_geometry();
+        let meta = Metadata::read(&self.data);
+        //only images with an embedded profile need to be converted, and only the ones
+        //that qcms can convert lose their profile
+        let encoding = &Encoding {
+            srgb: self.origin.convert_to_srgb.unwrap_or(true)
+                && meta.icc.is_some()
+                && img::has_srgb_transform(&self.data),
+            lossless_source: img::is_lossless(&self.data, &self.content_type),
+            ..self.output_encoding()
+        };
         let mut content_type = self.content_type.clone();
-        let data = match self.content_type.as_ref() {
-            "image/jpeg" | "image/jpg" => img::resize_static(&self.data, scale, ImageFormat::Jpeg),
-            "image/png" => match engine {
-                1 => img::resize_static(&self.data, scale, ImageFormat::Png),
-                _ => img::resize_png(&self.data, scale),
-            },
-            "image/webp" => img::resize_webp(&self.data, scale, img::is_webp_animated(&self.data)),
-            "image/gif" => img::resize_gif(&self.paths.base, &self.paths.modified, scale),
-            "image/svg+xml" => {
-                content_type = mime::IMAGE_PNG;
-                img::resize_static(&img::svg_to_png(&self.data)?, scale, ImageFormat::Png)
+        let data = if let Some(format) = self.conversion() {
+            content_type = format.mime();
+            match (self.content_type.essence_str(), format) {
+                ("image/gif", Format::Webp) => img::gif_to_webp(&self.data, geometry, encoding),
+                ("image/png", Format::Webp) if img::is_apng(&self.data) => {
+                    img::apng_to_webp(&self.data, geometry, encoding)
+                }
+                _ => img::transcode(&self.data, geometry, format, encoding),
             }
-            "video/mp4" => {
-                content_type = mime::IMAGE_GIF;
-                img::mp4_to_gif(&self.paths.base, &self.paths.modified, scale)
+        } else {
+            match self.content_type.as_ref() {
+                "image/jpeg" | "image/jpg" => {
+                    img::resize_static(&self.data, geometry, ImageFormat::Jpeg, encoding)
+                }
+                "image/png" if img::is_apng(&self.data) => {
+                    img::resize_apng(&self.data, geometry, encoding)
+                }
+                "image/png" => img::resize_png(&self.data, geometry, encoding),
+                "image/webp" => {
+                    let animated = img::is_webp_animated(&self.data);
+                    img::resize_webp(&self.data, geometry, animated, encoding)
+                }
+                "image/gif" => img::resize_gif(&self.data, geometry),
+                "image/avif" => img::transcode(&self.data, geometry, Format::Avif, encoding),
+                "image/svg+xml" if self.format == Some(Format::Svg) => svg::sanitize(&self.data),
+                "image/svg+xml" => {
+                    let format = self.format.unwrap_or(Format::Png);
+                    content_type = format.mime();
+                    img::render_svg(&self.data, geometry, format, encoding)
+                }
+                "video/mp4" => {
+                    let (base, tmp) = (&self.paths.base, &self.paths.tmp);
+                    let format = match (self.frame, self.format) {
+                        (Some(_), None) => Format::Jpeg,
+                        (_, format) => format.unwrap_or(Format::Gif),
+                    };
+                    content_type = format.mime();
+                    match (self.frame, format) {
+                        (None, Format::Gif) => img::mp4_to_gif(base, tmp, geometry, &self.video),
+                        (None, Format::Webp) => {
+                            img::mp4_to_webp(base, tmp, geometry, &self.video, encoding)
+                        }
+                        (None, Format::Mp4) => {
+                            img::resize_mp4(base, tmp, geometry, &self.video, encoding)
+                        }
+                        //still formats only need a single frame
+                        _ => {
+                            let frame = self.frame.unwrap_or(Frame::First);
+                            img::mp4_poster(base, tmp, geometry, frame, format, encoding)
+                        }
+                    }
+                }
+                //formats browsers can't display, and objects the origin didn't type
+                t if t == "application/octet-stream"
+                    || Format::fallback(&self.content_type).is_some() =>
+                {
+                    let format = self.format.unwrap_or(Format::Png);
+                    content_type = format.mime();
+                    img::transcode(&self.data, geometry, format, encoding)
+                }
+                "application/json" => Ok(self.data.clone()),
+                _ => {
+                    warn!(
+                        "Got unsupported format: {} - Skipping processing",
+                        self.content_type
+                    );
+                    Ok(self.data.clone())
+                }
             }
-            "application/octet-stream" => {
-                warn!(
-                    "Got unsupported format: {} - Trying to guess format from base.",
-                    self.content_type
-                );
-                content_type =
-                    utils::guess_content_type(&self.paths.base).unwrap_or(mime::IMAGE_PNG);
-                Ok(self.data.clone())
+        };
+        //encoders drop all metadata, so the kept blocks are copied over from the original.
+        //Pixels converted to sRGB don't need the original profile anymore.
+        let data = data.map(|d| {
+            let mut meta = meta.strip(encoding.strip());
+            if geometry.auto_orient {
+                meta.reset_orientation();
             }
-            "application/json" => Ok(self.data.clone()),
-            _ => {
-                warn!(
-                    "Got unsupported format: {} - Skipping processing",
-                    self.content_type
-                );
-                Ok(self.data.clone())
+            if encoding.srgb {
+                meta.icc = None;
             }
-        };
+            meta.write(&d).unwrap_or_else(|e| {
+                warn!("Unable to write metadata to obj from: {} - {}", self.url, e);
+                d
+            })
+        });
         let payload = match data {
             Ok(d) => d,
+            //the original can't stand in for a sanitized or rasterized svg
+            Err(e) if self.content_type.essence_str() == "image/svg+xml" => return Err(e),
             Err(e) => {
                 error!("Error while processing obj from: {} - {} -- Base Path: {} - Mod path: {} | Falling back to original", self.url, e, self.paths.base, self.paths.modified);
+                content_type = self.content_type.clone();
                 self.data.clone()
             }
         };
@@ -389,3 +647,20 @@ pub fn invalid_value(param: &str, value: String) -> HttpResponse {
     );
     HttpResponse::BadRequest().json(ErrorResponse::new(400, &msg))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_svg_is_not_served_as_is() {
+        let mut obj = Object::new("broken.svg");
+        obj.geometry(Geometry {
+            width: 100,
+            ..Default::default()
+        });
+        obj.content_type = mime::IMAGE_SVG;
+        obj.data = b"<svg><rect".to_vec();
+        assert!(obj.process().is_err());
+    }
+}
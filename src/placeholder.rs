@@ -0,0 +1,67 @@
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Hash that can be decoded into a blurry preview of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Blurhash,
+    Thumbhash,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 2] = [Kind::Blurhash, Kind::Thumbhash];
+}
+
+impl Default for Kind {
+    fn default() -> Self {
+        Self::Blurhash
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let kind = match self {
+            Self::Blurhash => "blurhash",
+            Self::Thumbhash => "thumbhash",
+        };
+        write!(out, "{}", kind)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Placeholder {
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    /// BlurHash string, or base64 encoded ThumbHash
+    pub hash: String,
+    /// Size of the original image
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Placeholder {
+    pub fn new(img: &DynamicImage, kind: Kind) -> Self {
+        //both hashes only keep the lowest frequencies, and ThumbHash takes 100px at most
+        let thumb = img.thumbnail(100, 100).to_rgba8();
+        let (w, h) = thumb.dimensions();
+        let hash = match kind {
+            Kind::Blurhash => {
+                let (x, y) = if w >= h { (4, 3) } else { (3, 4) };
+                blurhash::encode(x, y, w, h, thumb.as_raw())
+            }
+            Kind::Thumbhash => base64::encode(thumbhash::rgba_to_thumb_hash(
+                w as usize,
+                h as usize,
+                thumb.as_raw(),
+            )),
+        };
+        Self {
+            kind,
+            hash,
+            width: img.width(),
+            height: img.height(),
+        }
+    }
+}
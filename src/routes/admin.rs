@@ -1,10 +1,10 @@
 use super::ErrorResponse;
 use crate::config::AppConfig;
+use super::public::Params;
 use actix_web::{
-    web::{Data, Json},
+    web::{self, Data, Json},
     HttpResponse,
 };
-use std::collections::HashMap;
 
 use crate::object::{invalid_value, Object};
 
@@ -121,20 +121,38 @@ pub async fn create_invalidation(
             "Missing urls vec to invalidate. Ex: { urls: [\"https://assets.holaplex.tools/ipfs/<cid>?width=400&path=test.png\"] }",
         )));
     };
-    let mut objects: Vec<Object> = Vec::new();
+    //each object is invalidated with the exact path and query the clients request
+    let mut objects: Vec<(Object, String)> = Vec::new();
     //Create objects.
     for url in urls.iter() {
         match Url::parse(url) {
             Ok(url) => {
-                let pairs: HashMap<_, _> = url.query_pairs().into_owned().collect();
-                let scale = pairs
-                    .get("width")
-                    .unwrap_or(&"0".to_string())
-                    .parse::<u32>()?;
-                if let Some(q) = pairs.get("url") {
+                let cf_path = match url.query() {
+                    Some(q) => format!("{}?{}", url.path(), q),
+                    None => url.path().to_string(),
+                };
+                let params = web::Query::<Params>::from_query(url.query().unwrap_or_default())?;
+                let geometry = match params.geometry(&cfg) {
+                    Ok(g) => g,
+                    Err(res) => return Ok(res),
+                };
+                let encoding = match params.encoding(&cfg) {
+                    Ok(e) => e,
+                    Err(res) => return Ok(res),
+                };
+                let frame = match params.frame() {
+                    Ok(f) => f,
+                    Err(res) => return Ok(res),
+                };
+                let format = params.format;
+                if let Some(q) = &params.url {
                     let mut obj = Object::from_url(q.to_string());
-                    obj.scale(scale).set_paths(&cfg.storage_path);
-                    objects.push(obj)
+                    obj.geometry(geometry)
+                        .format(format)
+                        .frame(frame)
+                        .encoding(encoding)
+                        .set_paths(&cfg.storage_path);
+                    objects.push((obj, cf_path))
                 } else {
                     let mut paths = url.path_segments().unwrap();
                     let got_origin = &paths.next().unwrap_or_default();
@@ -144,12 +162,16 @@ pub async fn create_invalidation(
                     };
                     let filename = paths.next().unwrap_or_default();
                     let mut obj = Object::new(filename);
-                    obj.origin(&origin).scale(scale);
-                    if let Some(path) = &pairs.get("path") {
+                    obj.origin(&origin)
+                        .geometry(geometry)
+                        .format(format)
+                        .frame(frame)
+                        .encoding(encoding);
+                    if let Some(path) = &params.path {
                         obj.rename(path);
                     };
                     obj.set_paths(&cfg.storage_path);
-                    objects.push(obj)
+                    objects.push((obj, cf_path))
                 };
             }
             Err(e) => {
@@ -162,8 +184,8 @@ pub async fn create_invalidation(
     }
 
     let mut inv_paths = Vec::new();
-    for obj in objects.iter_mut() {
-        inv_paths.push(obj.get_cf_path());
+    for (obj, cf_path) in objects.iter_mut() {
+        inv_paths.push(cf_path.clone());
         obj.reset_retries(&client, &cfg).await?;
         obj.remove_paths()?;
     }
@@ -1,10 +1,17 @@
 use super::ErrorResponse;
+use crate::colors::{Colors, DEFAULT_COLORS, MAX_COLORS};
 use crate::config::AppConfig;
+use crate::img;
+use crate::img::{Encoding, Filter, Fit, Flip, Format, Frame, Geometry, Gravity, Rotate};
+use crate::info::MediaInfo;
+use crate::metadata::Strip;
 use crate::object::{invalid_value, Object};
+use crate::placeholder::{Kind, Placeholder};
 use crate::tw::TwitterProfile;
+use crate::utils;
 use actix_web::{
     error, get,
-    http::header::{CacheControl, CacheDirective},
+    http::header::{CacheControl, CacheDirective, ACCEPT, CONTENT_SECURITY_POLICY, VARY},
     web::{self, Data},
     HttpRequest, HttpResponse,
 };
@@ -13,13 +20,127 @@ use serde::Deserialize;
 use std::{str, time::Duration};
 use url::Url;
 
+/// Device pixel ratios accepted by the `dpr` query param
+const DPRS: [f32; 4] = [1.0, 1.5, 2.0, 3.0];
+/// Policy sent with SVGs, so scripts never run even when they are opened directly
+const SVG_CSP: &str = "default-src 'none'; style-src 'unsafe-inline'; img-src data:; sandbox";
+
 #[derive(Debug, Deserialize)]
 pub struct Params {
-    width: Option<u32>,
-    force: Option<bool>,
-    engine: Option<u32>,
-    path: Option<String>,
-    url: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub dpr: Option<f32>,
+    pub fit: Option<Fit>,
+    pub format: Option<Format>,
+    /// Still frame of a video: `first`, `middle` or `t=<seconds>`
+    pub frame: Option<String>,
+    pub quality: Option<u8>,
+    pub lossless: Option<bool>,
+    pub strip: Option<Strip>,
+    pub palette: Option<bool>,
+    pub crop: Option<String>,
+    pub aspect: Option<String>,
+    pub gravity: Option<Gravity>,
+    pub orient: Option<bool>,
+    pub rotate: Option<Rotate>,
+    pub flip: Option<Flip>,
+    pub force: Option<bool>,
+    pub filter: Option<Filter>,
+    pub background: Option<String>,
+    /// Deprecated and ignored, every format uses `filter` now
+    pub engine: Option<u32>,
+    pub path: Option<String>,
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlaceholderParams {
+    #[serde(rename = "type")]
+    pub kind: Option<Kind>,
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InfoParams {
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ColorsParams {
+    pub count: Option<u8>,
+    pub path: Option<String>,
+}
+
+impl Params {
+    /// Validates the resize and crop params
+    pub fn geometry(&self, cfg: &AppConfig) -> Result<Geometry, HttpResponse> {
+        if self.engine.is_some() {
+            log::warn!("The engine param is deprecated and ignored. Use filter instead");
+        }
+        let dpr = match self.dpr {
+            Some(d) if !DPRS.contains(&d) => return Err(invalid_value("dpr", d.to_string())),
+            d => d.unwrap_or(1.0),
+        };
+        let (width, height) = cfg
+            .validate_size(self.width, self.height, dpr)
+            .map_err(|(param, value)| invalid_value(param, value.to_string()))?;
+        let crop = match &self.crop {
+            Some(c) => Some(c.parse().map_err(|_| invalid_value("crop", c.clone()))?),
+            None => None,
+        };
+        let aspect = match &self.aspect {
+            Some(a) => Some(a.parse().map_err(|_| invalid_value("aspect", a.clone()))?),
+            None => None,
+        };
+        let background = match &self.background {
+            Some(b) => Some(
+                b.parse()
+                    .map_err(|_| invalid_value("background", b.clone()))?,
+            ),
+            None => None,
+        };
+        Ok(Geometry {
+            width,
+            height,
+            fit: self.fit.unwrap_or_default(),
+            crop,
+            aspect,
+            gravity: self.gravity.unwrap_or_default(),
+            auto_orient: self.orient.unwrap_or(true),
+            rotate: self.rotate,
+            flip: self.flip,
+            filter: self.filter,
+            background,
+        })
+    }
+
+    /// Validates the video frame param
+    pub fn frame(&self) -> Result<Option<Frame>, HttpResponse> {
+        match &self.frame {
+            //posters are stills
+            Some(f) if matches!(self.format, Some(Format::Mp4 | Format::Svg)) => {
+                Err(invalid_value("frame", f.clone()))
+            }
+            Some(f) => Ok(Some(
+                f.parse().map_err(|_| invalid_value("frame", f.clone()))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Validates the encoder params, on top of the defaults from the config
+    pub fn encoding(&self, cfg: &AppConfig) -> Result<Encoding, HttpResponse> {
+        match self.quality {
+            Some(q) if !(1..=100).contains(&q) => Err(invalid_value("quality", q.to_string())),
+            quality => Ok(Encoding {
+                quality,
+                lossless: self.lossless.unwrap_or(false),
+                strip: self.strip,
+                palette: self.palette,
+                ..cfg.encoding()
+            }),
+        }
+    }
 }
 
 pub async fn get_health_status() -> HttpResponse {
@@ -138,13 +259,25 @@ pub async fn get(
         )));
     };
 
-    let scale = match cfg.validate_scale(params.width) {
-        Some(s) => s,
-        None => return Ok(invalid_value("width", params.width.unwrap().to_string())),
+    let geometry = match params.geometry(&cfg) {
+        Ok(g) => g,
+        Err(res) => return Ok(res),
+    };
+    let encoding = match params.encoding(&cfg) {
+        Ok(e) => e,
+        Err(res) => return Ok(res),
+    };
+    let frame = match params.frame() {
+        Ok(f) => f,
+        Err(res) => return Ok(res),
     };
 
     let mut obj = Object::from_url(url.to_string());
-    obj.scale(scale);
+    obj.geometry(geometry)
+        .format(params.format)
+        .frame(frame)
+        .encoding(encoding)
+        .video(cfg.video.clone().unwrap_or_default());
     obj.set_paths(&cfg.storage_path)
         .try_open()?
         .create_dir(&cfg.storage_path)?;
@@ -158,15 +291,26 @@ pub async fn get(
         }
     }
 
+    //look for the variant in the format picked from the Accept header
+    let vary = obj.negotiate(req.headers().get(ACCEPT).and_then(|h| h.to_str().ok()));
+    if vary && obj.format.is_some() {
+        obj.set_paths(&cfg.storage_path)
+            .try_open()?
+            .create_dir(&cfg.storage_path)?;
+    }
+
     let valid_mod = std::path::Path::new(&obj.paths.modified).exists();
 
     let (content_type, payload) = if let Some(s) = obj.status {
         match s.is_success() && obj.is_valid() {
             true => {
-                if valid_mod || scale == 0 {
+                if let Err(res) = obj.check_params() {
+                    return Ok(res);
+                }
+                if valid_mod || !obj.is_modified() {
                     Ok((obj.content_type.clone(), obj.data.clone()))
                 } else {
-                    obj.process(params.engine.unwrap_or(0))
+                    obj.process()
                 }
             }
             false => {
@@ -186,13 +330,17 @@ pub async fn get(
 
     obj.save(payload.clone())?;
 
-    let res = HttpResponse::Ok()
-        .insert_header(CacheControl(vec![CacheDirective::MaxAge(
-            obj.origin.cache.max_age,
-        )]))
-        .content_type(content_type)
-        .body(payload);
-    Ok(res)
+    let mut res = HttpResponse::Ok();
+    res.insert_header(CacheControl(vec![CacheDirective::MaxAge(
+        obj.origin.cache.max_age,
+    )]));
+    if vary {
+        res.insert_header((VARY, "Accept"));
+    }
+    if content_type.essence_str() == mime::IMAGE_SVG.essence_str() {
+        res.insert_header((CONTENT_SECURITY_POLICY, SVG_CSP));
+    }
+    Ok(res.content_type(content_type).body(payload))
 }
 
 #[get("/{origin}/{filename}")]
@@ -211,13 +359,26 @@ pub async fn fetch_object(
         None => return Ok(invalid_value("origin", origin)),
     };
     //validate scaling param
-    let scale = match cfg.validate_scale(params.width) {
-        Some(s) => s,
-        None => return Ok(invalid_value("width", params.width.unwrap().to_string())),
+    let geometry = match params.geometry(&cfg) {
+        Ok(g) => g,
+        Err(res) => return Ok(res),
+    };
+    let encoding = match params.encoding(&cfg) {
+        Ok(e) => e,
+        Err(res) => return Ok(res),
+    };
+    let frame = match params.frame() {
+        Ok(f) => f,
+        Err(res) => return Ok(res),
     };
     //init object
     let mut obj = Object::new(&filename);
-    obj.origin(&origin).scale(scale);
+    obj.origin(&origin)
+        .geometry(geometry)
+        .format(params.format)
+        .frame(frame)
+        .encoding(encoding)
+        .video(cfg.video.clone().unwrap_or_default());
     if let Some(path) = &params.path {
         obj.rename(path);
     };
@@ -235,14 +396,25 @@ pub async fn fetch_object(
         }
     };
 
+    //look for the variant in the format picked from the Accept header
+    let vary = obj.negotiate(req.headers().get(ACCEPT).and_then(|h| h.to_str().ok()));
+    if vary && obj.format.is_some() {
+        obj.set_paths(&cfg.storage_path)
+            .try_open()?
+            .create_dir(&cfg.storage_path)?;
+    }
+
     let valid_mod = std::path::Path::new(&obj.paths.modified).exists();
     let (content_type, payload) = if let Some(s) = obj.status {
         match s.is_success() && obj.is_valid() {
             true => {
-                if obj.scale == 0 || valid_mod {
+                if let Err(res) = obj.check_params() {
+                    return Ok(res);
+                }
+                if !obj.is_modified() || valid_mod {
                     Ok((obj.content_type.clone(), obj.data.clone()))
                 } else {
-                    obj.process(params.engine.unwrap_or(0))
+                    obj.process()
                 }
             }
             false => {
@@ -262,11 +434,215 @@ pub async fn fetch_object(
 
     obj.save(payload.clone())?;
 
-    let res = HttpResponse::Ok()
+    let mut res = HttpResponse::Ok();
+    res.insert_header(CacheControl(vec![CacheDirective::MaxAge(
+        obj.origin.cache.max_age,
+    )]));
+    if vary {
+        res.insert_header((VARY, "Accept"));
+    }
+    if content_type.essence_str() == mime::IMAGE_SVG.essence_str() {
+        res.insert_header((CONTENT_SECURITY_POLICY, SVG_CSP));
+    }
+    Ok(res.content_type(content_type).body(payload))
+}
+
+/// Opens the original object from the cache, downloading it first when it's missing. Errors
+/// that should be sent to the client are returned as the inner `Err`.
+async fn base_object(
+    client: &Data<Client>,
+    cfg: &Data<AppConfig>,
+    origin: &str,
+    filename: &str,
+    path: Option<&str>,
+) -> Result<Result<Object, HttpResponse>, Box<dyn std::error::Error>> {
+    let origin = match cfg.validate_origin(origin) {
+        Some(o) => o,
+        None => return Ok(Err(invalid_value("origin", origin.to_string()))),
+    };
+    let mut obj = Object::new(filename);
+    obj.origin(&origin);
+    if let Some(path) = path {
+        obj.rename(path);
+    };
+    obj.set_paths(&cfg.storage_path)
+        .try_open()?
+        .create_dir(&cfg.storage_path)?;
+
+    if obj.data.is_empty() {
+        obj.get_retries(client, cfg).await?;
+        if obj.should_retry(cfg.max_retries) {
+            obj.download(client, cfg).await?;
+        } else {
+            return Ok(Err(obj.skip()?));
+        }
+    };
+    match obj.status {
+        Some(s) if s.is_success() && obj.is_valid() => Ok(Ok(obj)),
+        Some(_) => {
+            obj.remove_paths()?;
+            obj.update_retries(client, cfg).await?;
+            let msg = format!(
+                "Object downloaded from {}/{} is not valid",
+                obj.origin.name, obj.name
+            );
+            Ok(Err(
+                HttpResponse::InternalServerError().json(ErrorResponse::new(500, &msg))
+            ))
+        }
+        None => {
+            log::warn!("Error connecting to {}", obj.origin.name);
+            Ok(Err(HttpResponse::InternalServerError().finish()))
+        }
+    }
+}
+
+#[get("/placeholder/{origin}/{filename}")]
+pub async fn placeholder(
+    req: HttpRequest,
+    client: Data<Client>,
+    cfg: Data<AppConfig>,
+    data: web::Path<(String, String)>,
+) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+    let (origin, filename) = data.into_inner();
+    let params = match web::Query::<PlaceholderParams>::from_query(req.query_string()) {
+        Ok(p) => p,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse::new(400, &e.to_string())))
+        }
+    };
+    let kind = params.kind.unwrap_or_default();
+    let obj = match base_object(&client, &cfg, &origin, &filename, params.path.as_deref()).await? {
+        Ok(obj) => obj,
+        Err(res) => return Ok(res),
+    };
+
+    //hashes are stored next to the base object
+    let path = format!("{}.{}.json", obj.paths.base, kind);
+    let cached = utils::read_from_file(&path)
+        .ok()
+        .and_then(|d| serde_json::from_slice::<Placeholder>(&d).ok());
+    let hash = match cached {
+        Some(p) => p,
+        None => {
+            let img = match img::decode_upright(&obj.data, &obj.content_type) {
+                Ok(img) => img,
+                Err(e) => {
+                    let msg = format!("Unable to decode {}: {}", obj.content_type, e);
+                    return Ok(
+                        HttpResponse::UnsupportedMediaType().json(ErrorResponse::new(415, &msg))
+                    );
+                }
+            };
+            let hash = Placeholder::new(&img, kind);
+            utils::write_to_file(serde_json::to_vec(&hash)?, &path)?;
+            hash
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::MaxAge(
+            obj.origin.cache.max_age,
+        )]))
+        .json(hash))
+}
+
+#[get("/colors/{origin}/{filename}")]
+pub async fn colors(
+    req: HttpRequest,
+    client: Data<Client>,
+    cfg: Data<AppConfig>,
+    data: web::Path<(String, String)>,
+) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+    let (origin, filename) = data.into_inner();
+    let params = match web::Query::<ColorsParams>::from_query(req.query_string()) {
+        Ok(p) => p,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse::new(400, &e.to_string())))
+        }
+    };
+    let count = params.count.unwrap_or(DEFAULT_COLORS);
+    if !(1..=MAX_COLORS).contains(&count) {
+        return Ok(invalid_value("count", count.to_string()));
+    }
+    let obj = match base_object(&client, &cfg, &origin, &filename, params.path.as_deref()).await? {
+        Ok(obj) => obj,
+        Err(res) => return Ok(res),
+    };
+
+    //palettes are stored next to the base object, one per count
+    let path = format!("{}.colors-{}.json", obj.paths.base, count);
+    let cached = utils::read_from_file(&path)
+        .ok()
+        .and_then(|d| serde_json::from_slice::<Colors>(&d).ok());
+    let palette = match cached {
+        Some(c) => c,
+        None => {
+            let img = match img::decode_upright(&obj.data, &obj.content_type) {
+                Ok(img) => img,
+                Err(e) => {
+                    let msg = format!("Unable to decode {}: {}", obj.content_type, e);
+                    return Ok(
+                        HttpResponse::UnsupportedMediaType().json(ErrorResponse::new(415, &msg))
+                    );
+                }
+            };
+            let palette = Colors::new(&img, count);
+            utils::write_to_file(serde_json::to_vec(&palette)?, &path)?;
+            palette
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::MaxAge(
+            obj.origin.cache.max_age,
+        )]))
+        .json(palette))
+}
+
+#[get("/info/{origin}/{filename}")]
+pub async fn info(
+    req: HttpRequest,
+    client: Data<Client>,
+    cfg: Data<AppConfig>,
+    data: web::Path<(String, String)>,
+) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+    let (origin, filename) = data.into_inner();
+    let params = match web::Query::<InfoParams>::from_query(req.query_string()) {
+        Ok(p) => p,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse::new(400, &e.to_string())))
+        }
+    };
+    let obj = match base_object(&client, &cfg, &origin, &filename, params.path.as_deref()).await? {
+        Ok(obj) => obj,
+        Err(res) => return Ok(res),
+    };
+
+    let path = format!("{}.info.json", obj.paths.base);
+    let cached = utils::read_from_file(&path)
+        .ok()
+        .and_then(|d| serde_json::from_slice::<MediaInfo>(&d).ok());
+    let media = match cached {
+        Some(m) => m,
+        None => {
+            let media = match MediaInfo::new(&obj.data, &obj.content_type) {
+                Ok(m) => m,
+                Err(e) => {
+                    let msg = format!("Unable to read {}: {}", obj.content_type, e);
+                    return Ok(
+                        HttpResponse::UnsupportedMediaType().json(ErrorResponse::new(415, &msg))
+                    );
+                }
+            };
+            utils::write_to_file(serde_json::to_vec(&media)?, &path)?;
+            media
+        }
+    };
+
+    Ok(HttpResponse::Ok()
         .insert_header(CacheControl(vec![CacheDirective::MaxAge(
             obj.origin.cache.max_age,
         )]))
-        .content_type(content_type)
-        .body(payload);
-    Ok(res)
+        .json(media))
 }
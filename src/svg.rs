@@ -0,0 +1,259 @@
+use anyhow::{anyhow, Result};
+use quick_xml::events::{attributes::Attribute, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+
+/// Elements dropped with everything inside them
+const BLOCKED_ELEMENTS: [&str; 5] = ["script", "foreignobject", "iframe", "embed", "object"];
+
+/// Animation elements, which can rewrite any attribute of their parent
+const ANIMATION_ELEMENTS: [&str; 4] = ["set", "animate", "animatemotion", "animatetransform"];
+
+/// Embedded images that can't carry scripts
+const SAFE_DATA_URIS: [&str; 4] = [
+    "data:image/png",
+    "data:image/jpeg",
+    "data:image/gif",
+    "data:image/webp",
+];
+
+/// Rewrites an SVG without scripts, event handlers, foreign content or references to other
+/// documents. Comments, doctypes and whitespace between tags are dropped as well.
+pub fn sanitize(data: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = Reader::from_reader(data);
+    reader.trim_text(true);
+    let mut writer = Writer::new(Vec::with_capacity(data.len()));
+    let mut buf = Vec::new();
+    //depth inside a blocked element
+    let mut skipped = 0;
+    let mut has_root = false;
+    loop {
+        let event = reader.read_event_into(&mut buf)?;
+        match event {
+            Event::Eof => break,
+            Event::Start(e) if skipped > 0 || is_blocked(&e) => {
+                skipped += 1;
+            }
+            Event::End(_) if skipped > 0 => skipped -= 1,
+            _ if skipped > 0 => {}
+            Event::Empty(e) if is_blocked(&e) => {}
+            Event::Start(e) => {
+                has_root = true;
+                writer.write_event(Event::Start(clean_element(&e)?))?;
+            }
+            Event::Empty(e) => {
+                has_root = true;
+                writer.write_event(Event::Empty(clean_element(&e)?))?;
+            }
+            //styles can load other documents too
+            Event::Text(t) if matches!(t.unescape(), Ok(text) if is_safe_text(&text)) => {
+                writer.write_event(Event::Text(t))?
+            }
+            Event::CData(t) if matches!(std::str::from_utf8(&t), Ok(text) if is_safe_text(text)) => {
+                writer.write_event(Event::CData(t))?
+            }
+            Event::Text(_) | Event::CData(_) => {}
+            Event::End(e) => writer.write_event(Event::End(e))?,
+            //entities declared in a doctype could expand to anything
+            Event::Comment(_) | Event::DocType(_) | Event::PI(_) | Event::Decl(_) => {}
+        }
+        buf.clear();
+    }
+    if !has_root {
+        return Err(anyhow!("svg without elements"));
+    }
+    Ok(writer.into_inner())
+}
+
+/// Blocked elements, and animations that would swap a link target once the SVG is loaded
+fn is_blocked(e: &BytesStart) -> bool {
+    let name = String::from_utf8_lossy(e.local_name().as_ref()).to_ascii_lowercase();
+    if BLOCKED_ELEMENTS.contains(&name.as_str()) {
+        return true;
+    }
+    ANIMATION_ELEMENTS.contains(&name.as_str())
+        && e.attributes().flatten().any(|attr| {
+            attr.key
+                .local_name()
+                .as_ref()
+                .eq_ignore_ascii_case(b"attributeName")
+                && match attr.unescape_value() {
+                    Ok(v) => {
+                        let target = normalize(&v);
+                        target.ends_with("href") || target.ends_with("src")
+                    }
+                    Err(_) => true,
+                }
+        })
+}
+
+/// Copy of the element without event handlers and external references
+fn clean_element<'a>(e: &BytesStart<'a>) -> Result<BytesStart<'a>> {
+    let mut elem = e.clone();
+    elem.clear_attributes();
+    for attr in e.attributes() {
+        let attr = attr?;
+        if is_safe_attribute(&attr) {
+            elem.push_attribute(attr);
+        }
+    }
+    Ok(elem)
+}
+
+fn is_safe_attribute(attr: &Attribute) -> bool {
+    let name = String::from_utf8_lossy(attr.key.local_name().as_ref()).to_ascii_lowercase();
+    if name.starts_with("on") {
+        return false;
+    }
+    //values with unknown entities can't be checked
+    let value = match attr.unescape_value() {
+        Ok(v) => normalize(&v),
+        Err(_) => return false,
+    };
+    if name == "href" || name == "src" {
+        return value.starts_with('#') || SAFE_DATA_URIS.iter().any(|d| value.starts_with(d));
+    }
+    //animations can set any attribute, so scripts are blocked everywhere
+    !has_external_url(&value) && !has_script_uri(&value)
+}
+
+fn is_safe_text(text: &str) -> bool {
+    let text = normalize(text);
+    !text.contains("@import") && !has_external_url(&text) && !has_script_uri(&text)
+}
+
+fn has_script_uri(value: &str) -> bool {
+    value.contains("javascript:") || value.contains("vbscript:")
+}
+
+/// Whether a `url()` points to anything but a fragment of the document itself
+fn has_external_url(value: &str) -> bool {
+    value.match_indices("url(").any(|(i, _)| {
+        !value[i + 4..]
+            .trim_start_matches(['"', '\''])
+            .starts_with('#')
+    })
+}
+
+/// Value as browsers read it once XML entities are decoded: CSS escapes resolved, without
+/// whitespace or control characters, and lowercased
+fn normalize(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            //up to 6 hex digits, or any other escaped character as is
+            let mut hex = String::new();
+            while let Some(h) = chars
+                .peek()
+                .filter(|h| h.is_ascii_hexdigit() && hex.len() < 6)
+            {
+                hex.push(*h);
+                chars.next();
+            }
+            if hex.is_empty() {
+                match chars.next() {
+                    Some(c) => c,
+                    None => break,
+                }
+            } else {
+                u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER)
+            }
+        } else {
+            c
+        };
+        if !c.is_whitespace() && !c.is_control() {
+            out.extend(c.to_lowercase());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(svg: &str) -> String {
+        String::from_utf8(sanitize(svg.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn drops_scripts() {
+        let out = clean(r#"<svg><script>alert(1)</script><SCRIPT/><rect/></svg>"#);
+        assert_eq!(out, "<svg><rect/></svg>");
+    }
+
+    #[test]
+    fn drops_event_handlers() {
+        let out = clean(r#"<svg onload="alert(1)"><rect OnClick="alert(1)" fill="red"/></svg>"#);
+        assert_eq!(out, r#"<svg><rect fill="red"/></svg>"#);
+    }
+
+    #[test]
+    fn drops_script_hrefs() {
+        let out = clean(
+            r##"<svg><a href="javascript:alert(1)"/><a xlink:href=" java script:alert(1)"/><use href="#icon"/></svg>"##,
+        );
+        assert_eq!(out, r##"<svg><a/><a/><use href="#icon"/></svg>"##);
+    }
+
+    #[test]
+    fn keeps_only_image_data_hrefs() {
+        let out = clean(
+            r#"<svg><image href="data:image/png;base64,AAAA"/><image href="data:image/svg+xml,&lt;svg/&gt;"/><image href="data:text/html,x"/><image href="https://evil/x.png"/></svg>"#,
+        );
+        assert_eq!(
+            out,
+            r#"<svg><image href="data:image/png;base64,AAAA"/><image/><image/><image/></svg>"#
+        );
+    }
+
+    #[test]
+    fn drops_foreign_objects() {
+        let out = clean(
+            r#"<svg><foreignObject><body><iframe src="https://evil"/></body></foreignObject><rect/></svg>"#,
+        );
+        assert_eq!(out, "<svg><rect/></svg>");
+    }
+
+    #[test]
+    fn drops_animations_targeting_links() {
+        let out = clean(
+            r#"<svg><a><set attributeName="href" to="javascript:alert(1)"/><animate attributeName="xlink:href" values="https://evil"/><animate attributeName="opacity" from="0" to="1"/></a></svg>"#,
+        );
+        assert_eq!(
+            out,
+            r#"<svg><a><animate attributeName="opacity" from="0" to="1"/></a></svg>"#
+        );
+    }
+
+    #[test]
+    fn drops_external_styles() {
+        let out = clean(
+            r##"<svg><style>@import "https://evil/x.css";</style><style>rect { fill: url(https://evil/x) }</style><style>rect { fill: url(#grad) }</style><rect style="fill: url('https://evil/x')"/><rect style="fill: url('#grad')"/></svg>"##,
+        );
+        assert_eq!(
+            out,
+            r##"<svg><style></style><style></style><style>rect { fill: url(#grad) }</style><rect/><rect style="fill: url('#grad')"/></svg>"##
+        );
+    }
+
+    #[test]
+    fn drops_entity_encoded_payloads() {
+        let out = clean(
+            r#"<svg><a><set attributeName="hr&#x65;f" to="https://evil"/></a><rect fill="jav&#x61;script:alert(1)"/><rect fill="java&#9;script:alert(1)"/><rect style="fill:u&#x72;l(http://evil/)"/><style>rect { fill: u&#x72;l(http://evil/) }</style><style>rect { fill: u\72 l(http://evil/) }</style><style><![CDATA[@\69mport "https://evil/x.css";]]></style></svg>"#,
+        );
+        assert_eq!(
+            out,
+            "<svg><a></a><rect/><rect/><rect/><style></style><style></style><style></style></svg>"
+        );
+    }
+
+    #[test]
+    fn drops_unknown_entities() {
+        let out = clean(r#"<svg><rect fill="&evil;"/></svg>"#);
+        assert_eq!(out, "<svg><rect/></svg>");
+    }
+}
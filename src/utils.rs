@@ -2,8 +2,10 @@ use anyhow::Result;
 use cmd_lib::*;
 use mime::Mime;
 use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 pub struct Elapsed(Duration);
 impl Elapsed {
@@ -23,6 +25,32 @@ impl fmt::Display for Elapsed {
         }
     }
 }
+/// Directory with a unique name that is removed with everything inside when dropped
+pub struct TempDir {
+    pub path: String,
+}
+impl TempDir {
+    pub fn new(parent: &str) -> Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = format!(
+            "{}/{}-{}",
+            parent,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        //leftovers from a previous run with the same pid
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            log::warn!("Unable to remove temp dir {} - {}", self.path, e);
+        }
+    }
+}
 pub fn write_to_file(data: Vec<u8>, path: &str) -> Result<()> {
     let start = Instant::now();
     let mut file = File::create(path)?;